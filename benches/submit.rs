@@ -0,0 +1,63 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Contended writes through `iBag::with` versus `iBag::submit`.
+//!
+//! Run with `cargo bench --bench submit`. Every thread applies the same
+//! number of small mutations to one shared bag; the time includes a final
+//! `flush`, so both sides finish with every mutation applied.
+
+use ibag::iBag;
+use std::hint::black_box;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const OPS_PER_THREAD: u64 = 50_000;
+const ROUNDS: usize = 5;
+
+fn mutate(total: &mut u64, n: u64) {
+    *total = total.wrapping_mul(31).wrapping_add(n);
+}
+
+/// Returns the best of `ROUNDS` runs of `threads` threads each calling
+/// `write` `OPS_PER_THREAD` times
+fn measure<F>(threads: usize, write: F) -> Duration
+where
+    F: Fn(&iBag<u64>, u64) + Sync,
+{
+    (0..ROUNDS)
+        .map(|_| {
+            let bag = iBag::new(0u64);
+            let start = Instant::now();
+            thread::scope(|s| {
+                for _ in 0..threads {
+                    s.spawn(|| {
+                        for n in 0..OPS_PER_THREAD {
+                            write(&bag, n);
+                        }
+                    });
+                }
+            });
+            bag.flush();
+            let elapsed = start.elapsed();
+            black_box(*bag.load());
+            elapsed
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    println!("{:>7} {:>12} {:>12}", "threads", "with ns/op", "submit ns/op");
+    for threads in [1, 2, 4, 8, 16] {
+        let ops = (threads as u64 * OPS_PER_THREAD) as f64;
+        let with = measure(threads, |bag, n| bag.with(|total| mutate(total, n)));
+        let submit = measure(threads, |bag, n| bag.submit(move |total| mutate(total, n)));
+        println!(
+            "{:>7} {:>12.1} {:>12.1}",
+            threads,
+            with.as_nanos() as f64 / ops,
+            submit.as_nanos() as f64 / ops
+        );
+    }
+}
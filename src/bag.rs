@@ -2,14 +2,107 @@
 // Licensed under the MIT license (https://opensource.org/licenses/MIT)
 
 #![allow(non_camel_case_types)]
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::ptr::NonNull;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use crate::errors::{PoisonedBag, TryLockError, VersionConflict};
+use crate::builder::BagBuilder;
+use crate::combine::{Combiner, BATCH_LIMIT};
+use crate::lock::{ExclusiveHold, LockPolicy, RawLock, SharedHold, UpgradableHold, Wait};
+#[cfg(feature = "metrics")]
+use crate::metrics::LockStats;
+use crate::frozen::FrozenBag;
+use crate::future::{ReadFuture, WriteFuture};
+use crate::history::{History, HistoryConfig};
+use crate::handle::{iBagReader, iBagWriter};
+use crate::project::Projection;
+use crate::registry;
+use crate::watch::Watcher;
 
 /// A thread-safe, immutable bag for holding any value
-#[derive(Debug)]
+///
+/// # Thread Safety
+/// Like `Arc<RwLock<T>>`, an `iBag<T>` is `Send` and `Sync` only if `T` is
+/// `Send + Sync`: every clone hands out `&T` to many threads at once and
+/// `&mut T` to one thread at a time.
+///
+/// A value that is not `Send`, such as an `Rc`, can still live in a shared
+/// bag inside an `iCell`, which is `Send` and `Sync` for any `T` and only
+/// lets the thread that created it touch the value.
+///
+/// ```
+/// use ibag::{iBag, iCell};
+/// use std::rc::Rc;
+/// use std::thread;
+///
+/// let bag = iBag::new(iCell::new(Rc::new(1), false));
+/// let b = bag.clone();
+/// thread::spawn(move || assert!(b.with_read(|cell| cell.try_get().is_err())))
+///     .join()
+///     .unwrap();
+/// assert_eq!(bag.with_read(|cell| **cell.try_get().unwrap()), 1);
+/// ```
+///
+/// Bags of values that are not `Send + Sync` are neither:
+///
+/// ```compile_fail
+/// use ibag::iBag;
+/// use std::rc::Rc;
+///
+/// fn assert_send<T: Send>() {}
+/// assert_send::<iBag<Rc<i32>>>();
+/// ```
+///
+/// ```compile_fail
+/// use ibag::iBag;
+/// use std::rc::Rc;
+///
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<iBag<Rc<i32>>>();
+/// ```
+///
+/// ```compile_fail
+/// use ibag::iBag;
+/// use std::cell::Cell;
+///
+/// fn assert_send<T: Send>() {}
+/// assert_send::<iBag<Cell<i32>>>();
+/// ```
+///
+/// ```compile_fail
+/// use ibag::iBag;
+/// use std::cell::Cell;
+///
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<iBag<Cell<i32>>>();
+/// ```
 pub struct iBag<T: Sized> {
-    inner: Arc<RwLock<T>>,
+    inner: Arc<Inner<T>>,
+}
+
+struct Inner<T> {
+    raw: RawLock,
+    value: UnsafeCell<T>,
+    history: Option<History<T>>,
+    combiner: Combiner<T>,
 }
 
+// Same bounds as `std::sync::RwLock`: the lock hands out `&mut T` to one
+// thread at a time and `&T` to many threads at once.
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send + Sync> Sync for Inner<T> {}
+
+// Also as for `RwLock`: a writer that panics poisons the bag, so code that
+// catches the panic cannot observe a half-updated value by accident.
+impl<T> UnwindSafe for Inner<T> {}
+impl<T> RefUnwindSafe for Inner<T> {}
+
 impl<T> iBag<T> where T: Sized {
     /// Creates a new iBag instance wrapping the given value
     ///
@@ -18,85 +111,1832 @@ impl<T> iBag<T> where T: Sized {
     /// use ibag::iBag;
     /// let bag = iBag::new(42);
     /// ```
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
     pub fn new(value: T) -> Self {
-        Self {
-            inner: Arc::new(RwLock::new(value)),
+        Self::with_policy(value, LockPolicy::default())
+    }
+
+    /// Creates a new iBag whose lock follows the given policy
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::{iBag, LockPolicy};
+    /// let bag = iBag::with_policy(42, LockPolicy::Fair);
+    /// assert_eq!(bag.policy(), LockPolicy::Fair);
+    /// ```
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn with_policy(value: T, policy: LockPolicy) -> Self {
+        Self::builder().policy(policy).build(value)
+    }
+
+    /// Creates a new iBag that is listed under `name` in the registry
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::named("session-cache", vec![1, 2]);
+    /// assert_eq!(bag.name(), Some("session-cache"));
+    /// ```
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn named(name: impl Into<String>, value: T) -> Self {
+        Self::builder().name(name).build(value)
+    }
+
+    /// Creates a new iBag that keeps up to `capacity` earlier values for
+    /// `undo` and `redo`
+    ///
+    /// Every write records a copy of the value it starts from. Use
+    /// `BagBuilder::history_budget` to bound the history by size instead.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::with_history(String::from("a"), 10);
+    /// bag.with(|doc| doc.push('b'));
+    /// bag.with(|doc| doc.push('c'));
+    /// assert!(bag.undo());
+    /// assert_eq!(*bag.load(), "ab");
+    /// assert!(bag.redo());
+    /// assert_eq!(*bag.load(), "abc");
+    /// ```
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn with_history(value: T, capacity: usize) -> Self
+    where
+        T: Clone,
+    {
+        Self::builder().history(capacity).build(value)
+    }
+
+    pub(crate) fn from_lock(raw: RawLock, value: T, history: Option<HistoryConfig<T>>) -> Self {
+        let inner = Arc::new(Inner {
+            raw,
+            value: UnsafeCell::new(value),
+            history: history.map(History::new),
+            combiner: Combiner::new(),
+        });
+        inner.raw.register();
+        Self { inner }
+    }
+
+    /// Returns a builder for configuring a new iBag
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::{iBag, LockPolicy};
+    /// let bag = iBag::builder().policy(LockPolicy::ReaderPreferring).build(42);
+    /// assert_eq!(*bag.load(), 42);
+    /// ```
+    pub fn builder() -> BagBuilder<T> {
+        BagBuilder::new()
+    }
+
+    /// Returns the lock policy the bag was created with
+    pub fn policy(&self) -> LockPolicy {
+        self.inner.raw.policy()
+    }
+
+    /// Returns the name the bag was created with, if any
+    pub fn name(&self) -> Option<&str> {
+        self.inner.raw.name()
+    }
+
+    /// Returns a snapshot of the bag's lock metrics
+    ///
+    /// Requires the `metrics` feature.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(0);
+    /// bag.with(|val| *val += 1);
+    /// bag.with_read(|val| *val);
+    /// let stats = bag.stats();
+    /// assert_eq!(stats.write.acquisitions, 1);
+    /// assert_eq!(stats.read.acquisitions, 1);
+    /// assert_eq!(stats.write.hold.count(), 1);
+    /// ```
+    #[cfg(feature = "metrics")]
+    pub fn stats(&self) -> LockStats {
+        self.inner.raw.metrics().snapshot()
+    }
+
+    pub(crate) fn raw(&self) -> &RawLock {
+        &self.inner.raw
+    }
+
+    /// Address of the shared allocation, the same for every handle to this
+    /// bag; used to lock several bags in a global order
+    pub(crate) fn addr(&self) -> usize {
+        Arc::as_ptr(&self.inner) as usize
+    }
+
+    fn read_guard<'a>(&'a self, hold: SharedHold<'a>) -> ReadGuard<'a, T> {
+        ReadGuard {
+            _hold: hold,
+            data: unsafe { NonNull::new_unchecked(self.inner.value.get()) },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Wraps exclusive access in a guard without taking a history snapshot
+    fn unrecorded_write_guard<'a>(&'a self, hold: ExclusiveHold<'a>) -> WriteGuard<'a, T> {
+        WriteGuard {
+            hold,
+            data: unsafe { NonNull::new_unchecked(self.inner.value.get()) },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Takes the history snapshot for a writer that just got access
+    fn record(&self) {
+        if let Some(history) = &self.inner.history {
+            history.record(unsafe { &*self.inner.value.get() }, self.version());
+        }
+    }
+
+    /// Wraps acquired shared access in a guard, refusing a poisoned bag
+    pub(crate) fn checked_read<'a>(&'a self, hold: SharedHold<'a>) -> Result<ReadGuard<'a, T>, PoisonedBag> {
+        let guard = self.read_guard(hold);
+        if self.is_poisoned() {
+            return Err(PoisonedBag);
+        }
+        Ok(guard)
+    }
+
+    /// Wraps acquired exclusive access in a guard, refusing a poisoned bag
+    pub(crate) fn checked_write<'a>(&'a self, hold: ExclusiveHold<'a>) -> Result<WriteGuard<'a, T>, PoisonedBag> {
+        let mut guard = self.unrecorded_write_guard(hold);
+        if self.is_poisoned() {
+            guard.mark_unchanged();
+            return Err(PoisonedBag);
+        }
+        self.record();
+        Ok(guard)
+    }
+
+    fn acquire_read(&self, wait: Wait) -> Result<ReadGuard<'_, T>, TryLockError> {
+        Ok(self.checked_read(self.inner.raw.lock_shared(wait)?)?)
+    }
+
+    fn acquire_write(&self, wait: Wait) -> Result<WriteGuard<'_, T>, TryLockError> {
+        Ok(self.checked_write(self.inner.raw.lock_exclusive(wait)?)?)
+    }
+
+    fn acquire_upgradable(&self, wait: Wait) -> Result<UpgradableReadGuard<'_, T>, TryLockError> {
+        let hold = self.inner.raw.lock_upgradable(wait)?;
+        if self.is_poisoned() {
+            return Err(PoisonedBag.into());
+        }
+        Ok(UpgradableReadGuard {
+            hold,
+            bag: self,
+            data: unsafe { NonNull::new_unchecked(self.inner.value.get()) },
+            _marker: PhantomData,
+        })
+    }
+
+    /// Acquires a read lock on the contained value
+    ///
+    /// # Safety
+    /// - The returned guard must not outlive the iBag
+    /// - The guard provides thread-safe read-only access
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// let guard = bag.load();
+    /// assert_eq!(*guard, 42);
+    /// ```
+    pub fn load(&self) -> ReadGuard<'_, T> {
+        self.try_load().unwrap()
+    }
+
+    /// Acquires a write lock on the contained value
+    ///
+    /// # Safety
+    /// - The returned guard must not outlive the iBag
+    /// - The guard provides exclusive mutable access
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// let mut guard = bag.write();
+    /// *guard = 100;
+    /// ```
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        self.try_write().unwrap()
+    }
+
+    /// Executes a closure with mutable access to the contained value
+    ///
+    /// # Thread Safety
+    /// - The closure executes with exclusive access
+    /// - Automatically releases the lock when done
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// bag.with(|val| *val = 100);
+    /// ```
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let mut guard = self.write();
+        f(&mut *guard)
+    }
+
+    /// Executes a closure with read-only access to the contained value
+    ///
+    /// # Thread Safety
+    /// - The closure executes with shared read access
+    /// - Automatically releases the lock when done
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// let result = bag.with_read(|val| *val);
+    /// assert_eq!(result, 42);
+    /// ```
+    pub fn with_read<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let guard = self.load();
+        f(&*guard)
+    }
+
+    /// Acquires an upgradable read lock on the contained value
+    ///
+    /// The guard coexists with plain readers but excludes writers and other
+    /// upgradable readers, so it can later be upgraded to a write guard
+    /// without another writer getting in first.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::{iBag, UpgradableReadGuard};
+    ///
+    /// let bag = iBag::new(vec![1, 2]);
+    /// let guard = bag.upgradable_load();
+    /// if !guard.contains(&3) {
+    ///     UpgradableReadGuard::upgrade(guard).push(3);
+    /// }
+    /// assert_eq!(*bag.load(), vec![1, 2, 3]);
+    /// ```
+    pub fn upgradable_load(&self) -> UpgradableReadGuard<'_, T> {
+        self.try_upgradable_load().unwrap()
+    }
+
+    /// Attempts to acquire an upgradable read lock without panicking on poison
+    ///
+    /// # Returns
+    /// - `Ok(guard)` if the lock was acquired
+    /// - `Err(PoisonedBag)` if a writer panicked while holding the lock
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// assert_eq!(*bag.try_upgradable_load().unwrap(), 42);
+    /// ```
+    pub fn try_upgradable_load(&self) -> Result<UpgradableReadGuard<'_, T>, PoisonedBag> {
+        blocking(self.acquire_upgradable(Wait::Forever))
+    }
+
+    /// Attempts to acquire a read lock without panicking on poison
+    ///
+    /// # Returns
+    /// - `Ok(guard)` if the lock was acquired
+    /// - `Err(PoisonedBag)` if a writer panicked while holding the lock
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// assert_eq!(*bag.try_load().unwrap(), 42);
+    /// ```
+    pub fn try_load(&self) -> Result<ReadGuard<'_, T>, PoisonedBag> {
+        blocking(self.acquire_read(Wait::Forever))
+    }
+
+    /// Attempts to acquire a write lock without panicking on poison
+    ///
+    /// # Returns
+    /// - `Ok(guard)` if the lock was acquired
+    /// - `Err(PoisonedBag)` if a writer panicked while holding the lock
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// *bag.try_write().unwrap() = 100;
+    /// assert_eq!(*bag.load(), 100);
+    /// ```
+    pub fn try_write(&self) -> Result<WriteGuard<'_, T>, PoisonedBag> {
+        blocking(self.acquire_write(Wait::Forever))
+    }
+
+    /// Executes a closure with mutable access, failing instead of panicking
+    /// when the bag is poisoned
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// assert_eq!(bag.try_with(|val| { *val += 1; *val }).unwrap(), 43);
+    /// ```
+    pub fn try_with<F, R>(&self, f: F) -> Result<R, PoisonedBag>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let mut guard = self.try_write()?;
+        Ok(f(&mut *guard))
+    }
+
+    /// Executes a closure with read-only access, failing instead of panicking
+    /// when the bag is poisoned
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// assert_eq!(bag.try_with_read(|val| *val).unwrap(), 42);
+    /// ```
+    pub fn try_with_read<F, R>(&self, f: F) -> Result<R, PoisonedBag>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let guard = self.try_load()?;
+        Ok(f(&*guard))
+    }
+
+    /// Returns `true` if a writer panicked while holding the lock
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// assert!(!bag.is_poisoned());
+    /// ```
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.raw.is_poisoned()
+    }
+
+    /// Clears the poisoned state, putting the bag back into service as is
+    ///
+    /// Prefer `recover_poison` when the value may have been left in an
+    /// inconsistent state by the panicking writer.
+    pub fn clear_poison(&self) {
+        self.inner.raw.clear_poison();
+    }
+
+    /// Gives a supervisor write access to a possibly poisoned value
+    ///
+    /// The closure inspects and repairs the value. If it returns `true` the
+    /// poison flag is cleared and the bag is put back into service, otherwise
+    /// the bag stays poisoned. The poisoned value is not recorded in history.
+    ///
+    /// # Returns
+    /// `true` if the bag is healthy afterwards
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use std::thread;
+    ///
+    /// let bag = iBag::new(vec![1, 2, 3]);
+    /// let b = bag.clone();
+    /// let _ = thread::spawn(move || b.with(|_| panic!("boom"))).join();
+    /// assert!(bag.try_load().is_err());
+    ///
+    /// assert!(bag.recover_poison(|v| { v.clear(); true }));
+    /// assert!(bag.try_load().unwrap().is_empty());
+    /// ```
+    pub fn recover_poison<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&mut T) -> bool,
+    {
+        let hold = match self.inner.raw.lock_exclusive(Wait::Forever) {
+            Ok(hold) => hold,
+            Err(_) => unreachable!("blocking acquisition cannot fail"),
+        };
+        let mut guard = self.unrecorded_write_guard(hold);
+        // A poisoned value is not a state worth undoing back to.
+        if !self.is_poisoned() {
+            self.record();
+        }
+        let healthy = f(&mut *guard);
+        drop(guard);
+        if healthy {
+            self.clear_poison();
         }
+        !self.is_poisoned()
+    }
+
+    /// Acquires a read lock only if it is available right now
+    ///
+    /// # Returns
+    /// - `Ok(guard)` if the lock was acquired
+    /// - `Err(TryLockError::WouldBlock)` if a writer holds or is waiting for the lock
+    /// - `Err(TryLockError::Poisoned(_))` if the bag is poisoned
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use ibag::errors::TryLockError;
+    ///
+    /// let bag = iBag::new(42);
+    /// let guard = bag.write();
+    /// assert_eq!(bag.try_load_now().err(), Some(TryLockError::WouldBlock));
+    /// drop(guard);
+    /// assert_eq!(*bag.try_load_now().unwrap(), 42);
+    /// ```
+    pub fn try_load_now(&self) -> Result<ReadGuard<'_, T>, TryLockError> {
+        self.acquire_read(Wait::Never)
+    }
+
+    /// Acquires a write lock only if it is available right now
+    ///
+    /// # Returns
+    /// - `Ok(guard)` if the lock was acquired
+    /// - `Err(TryLockError::WouldBlock)` if the lock is held
+    /// - `Err(TryLockError::Poisoned(_))` if the bag is poisoned
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use ibag::errors::TryLockError;
+    ///
+    /// let bag = iBag::new(42);
+    /// let guard = bag.load();
+    /// assert_eq!(bag.try_write_now().err(), Some(TryLockError::WouldBlock));
+    /// drop(guard);
+    /// *bag.try_write_now().unwrap() = 100;
+    /// ```
+    pub fn try_write_now(&self) -> Result<WriteGuard<'_, T>, TryLockError> {
+        self.acquire_write(Wait::Never)
+    }
+
+    /// Acquires a read lock, waiting at most `timeout`
+    ///
+    /// # Returns
+    /// - `Ok(guard)` if the lock was acquired in time
+    /// - `Err(TryLockError::TimedOut)` if the timeout elapsed first
+    /// - `Err(TryLockError::Poisoned(_))` if the bag is poisoned
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use std::time::Duration;
+    ///
+    /// let bag = iBag::new(42);
+    /// let guard = bag.load_timeout(Duration::from_millis(10)).unwrap();
+    /// assert_eq!(*guard, 42);
+    /// ```
+    pub fn load_timeout(&self, timeout: Duration) -> Result<ReadGuard<'_, T>, TryLockError> {
+        self.acquire_read(Wait::timeout(timeout))
+    }
+
+    /// Acquires a write lock, waiting at most `timeout`
+    ///
+    /// # Returns
+    /// - `Ok(guard)` if the lock was acquired in time
+    /// - `Err(TryLockError::TimedOut)` if the timeout elapsed first
+    /// - `Err(TryLockError::Poisoned(_))` if the bag is poisoned
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use ibag::errors::TryLockError;
+    /// use std::time::Duration;
+    ///
+    /// let bag = iBag::new(42);
+    /// let guard = bag.load();
+    /// let result = bag.write_timeout(Duration::from_millis(10));
+    /// assert_eq!(result.err(), Some(TryLockError::TimedOut));
+    /// ```
+    pub fn write_timeout(&self, timeout: Duration) -> Result<WriteGuard<'_, T>, TryLockError> {
+        self.acquire_write(Wait::timeout(timeout))
+    }
+
+    /// Acquires an upgradable read lock only if it is available right now
+    ///
+    /// # Returns
+    /// - `Ok(guard)` if the lock was acquired
+    /// - `Err(TryLockError::WouldBlock)` if a writer or another upgradable reader is in the way
+    /// - `Err(TryLockError::Poisoned(_))` if the bag is poisoned
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use ibag::errors::TryLockError;
+    ///
+    /// let bag = iBag::new(42);
+    /// let guard = bag.upgradable_load();
+    /// assert_eq!(bag.try_upgradable_load_now().err(), Some(TryLockError::WouldBlock));
+    /// drop(guard);
+    /// assert_eq!(*bag.try_upgradable_load_now().unwrap(), 42);
+    /// ```
+    pub fn try_upgradable_load_now(&self) -> Result<UpgradableReadGuard<'_, T>, TryLockError> {
+        self.acquire_upgradable(Wait::Never)
+    }
+
+    /// Acquires an upgradable read lock, waiting at most `timeout`
+    ///
+    /// # Returns
+    /// - `Ok(guard)` if the lock was acquired in time
+    /// - `Err(TryLockError::TimedOut)` if the timeout elapsed first
+    /// - `Err(TryLockError::Poisoned(_))` if the bag is poisoned
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use ibag::errors::TryLockError;
+    /// use std::time::Duration;
+    ///
+    /// let bag = iBag::new(42);
+    /// let guard = bag.write();
+    /// let result = bag.upgradable_load_timeout(Duration::from_millis(10));
+    /// assert_eq!(result.err(), Some(TryLockError::TimedOut));
+    /// ```
+    pub fn upgradable_load_timeout(&self, timeout: Duration) -> Result<UpgradableReadGuard<'_, T>, TryLockError> {
+        self.acquire_upgradable(Wait::timeout(timeout))
+    }
+
+    /// Executes a closure with mutable access, waiting at most `timeout`
+    /// for the write lock
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use std::time::Duration;
+    ///
+    /// let bag = iBag::new(42);
+    /// let result = bag.with_timeout(Duration::from_millis(10), |val| {
+    ///     *val += 1;
+    ///     *val
+    /// });
+    /// assert_eq!(result, Ok(43));
+    /// ```
+    pub fn with_timeout<F, R>(&self, timeout: Duration, f: F) -> Result<R, TryLockError>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let mut guard = self.write_timeout(timeout)?;
+        Ok(f(&mut *guard))
+    }
+
+    /// Executes a closure with read-only access, waiting at most `timeout`
+    /// for the read lock
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use std::time::Duration;
+    ///
+    /// let bag = iBag::new(42);
+    /// assert_eq!(bag.with_read_timeout(Duration::from_millis(10), |val| *val), Ok(42));
+    /// ```
+    pub fn with_read_timeout<F, R>(&self, timeout: Duration, f: F) -> Result<R, TryLockError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let guard = self.load_timeout(timeout)?;
+        Ok(f(&*guard))
+    }
+
+    /// Subscribes to changes of the contained value
+    ///
+    /// The returned `Watcher` is notified whenever a write guard is
+    /// released, including the ones taken by `with`. Changes made before
+    /// the call are considered seen.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use std::thread;
+    ///
+    /// let bag = iBag::new(0);
+    /// let mut watcher = bag.subscribe();
+    ///
+    /// let b = bag.clone();
+    /// thread::spawn(move || b.with(|val| *val = 1));
+    ///
+    /// watcher.changed();
+    /// assert_eq!(*watcher.borrow_latest(), 1);
+    /// ```
+    pub fn subscribe(&self) -> Watcher<T> {
+        Watcher::new(self.clone())
+    }
+
+    /// Returns the current version of the contained value
+    ///
+    /// The version starts at 0 and is bumped every time a write guard is
+    /// released, including the ones taken by `with`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// assert_eq!(bag.version(), 0);
+    /// bag.with(|val| *val = 100);
+    /// assert_eq!(bag.version(), 1);
+    /// ```
+    pub fn version(&self) -> u64 {
+        self.inner.raw.version()
+    }
+
+    /// Executes a closure with read-only access to the contained value and
+    /// the version it belongs to
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// let (version, value) = bag.with_read_versioned(|v, val| (v, *val));
+    /// assert_eq!((version, value), (0, 42));
+    /// ```
+    pub fn with_read_versioned<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(u64, &T) -> R,
+    {
+        let guard = self.load();
+        // Writers are excluded while the read lock is held.
+        f(self.version(), &*guard)
+    }
+
+    /// Executes a closure with mutable access only if the bag is still at
+    /// the `expected` version
+    ///
+    /// Used for optimistic read-modify-write loops: read the value and its
+    /// version with `with_read_versioned`, compute without holding a lock,
+    /// then commit with `with_if_version`. A rejected call neither bumps the
+    /// version nor takes a history snapshot.
+    ///
+    /// # Returns
+    /// - `Ok(result)` if the closure ran
+    /// - `Err(VersionConflict)` if another writer got in first
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(1);
+    /// let (version, value) = bag.with_read_versioned(|v, val| (v, *val));
+    ///
+    /// bag.with(|val| *val = 10);
+    /// let conflict = bag.with_if_version(version, |val| *val = value + 1).unwrap_err();
+    /// assert_eq!(conflict.actual, version + 1);
+    ///
+    /// let version = bag.version();
+    /// assert!(bag.with_if_version(version, |val| *val += 1).is_ok());
+    /// assert_eq!(*bag.load(), 11);
+    /// ```
+    pub fn with_if_version<F, R>(&self, expected: u64, f: F) -> Result<R, VersionConflict>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let hold = match self.inner.raw.lock_exclusive(Wait::Forever) {
+            Ok(hold) => hold,
+            Err(_) => unreachable!("blocking acquisition cannot fail"),
+        };
+        let mut guard = self.unrecorded_write_guard(hold);
+        if self.is_poisoned() {
+            guard.mark_unchanged();
+            panic!("{}", PoisonedBag);
+        }
+        let actual = self.version();
+        if actual != expected {
+            guard.mark_unchanged();
+            return Err(VersionConflict { expected, actual });
+        }
+        // Only a write that goes ahead is worth a snapshot.
+        self.record();
+        Ok(f(&mut *guard))
+    }
+
+    /// Queues a mutation to be applied by whichever thread is combining
+    ///
+    /// If no other thread is applying queued mutations, the caller takes
+    /// the write lock and applies everything queued, including what other
+    /// threads submit meanwhile, before returning. Otherwise it returns
+    /// right away. Under heavy write contention this replaces a lock
+    /// handoff per mutation with one per batch.
+    ///
+    /// Mutations run in the order they were submitted. A batch of up to 64
+    /// mutations is one write: watchers are notified once and the version
+    /// moves by one. Between batches the write lock is released, so
+    /// readers get their turn. Use `flush` to wait until a mutation has
+    /// been applied.
+    ///
+    /// # Panics
+    /// If the bag is poisoned, or a queued mutation panics, while the
+    /// caller is combining. Queued mutations are then discarded.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use std::thread;
+    ///
+    /// let log = iBag::new(Vec::new());
+    /// thread::scope(|s| {
+    ///     for n in 0..4 {
+    ///         let log = &log;
+    ///         s.spawn(move || log.submit(move |lines| lines.push(n)));
+    ///     }
+    /// });
+    /// log.flush();
+    /// assert_eq!(log.load().len(), 4);
+    /// ```
+    pub fn submit<F>(&self, f: F)
+    where
+        F: FnOnce(&mut T) + Send + 'static,
+    {
+        if let Some(mut combining) = self.inner.combiner.push(Box::new(f)) {
+            // Declared after `combining`, so a panic releases it first.
+            let mut guard = self.write();
+            let mut budget = BATCH_LIMIT;
+            while let Some(batch) = combining.next_batch(if budget == 0 { BATCH_LIMIT } else { budget }) {
+                if budget == 0 {
+                    // Hand the lock over only when there is more to do.
+                    drop(guard);
+                    combining.released();
+                    guard = self.write();
+                    budget = BATCH_LIMIT;
+                }
+                budget -= batch.len();
+                for op in batch {
+                    op(&mut guard);
+                }
+            }
+            drop(guard);
+            combining.released();
+        }
+    }
+
+    /// Blocks until every mutation submitted before the call has been
+    /// applied, or discarded because the bag was poisoned
+    ///
+    /// Applied mutations have had their write lock released, so `version`
+    /// and watchers already reflect them.
+    ///
+    /// Must not be called from inside a mutation or while holding a lock
+    /// on this bag, since the combiner needs the write lock to finish.
+    pub fn flush(&self) {
+        self.inner.combiner.flush();
+    }
+
+    /// Blocks until the contained value satisfies `pred`
+    ///
+    /// The predicate is checked under a read lock, then again after every
+    /// released write guard, with the caller parked in between. The guard
+    /// it returns is the read lock under which `pred` returned `true`, so
+    /// the value cannot change before the caller looks at it.
+    ///
+    /// # Panics
+    /// If the bag is or becomes poisoned while waiting.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use std::thread;
+    ///
+    /// let queue = iBag::new(Vec::new());
+    /// let q = queue.clone();
+    /// thread::spawn(move || q.with(|jobs| jobs.push("job")));
+    ///
+    /// let jobs = queue.wait_until(|jobs| !jobs.is_empty());
+    /// assert_eq!(jobs[0], "job");
+    /// ```
+    pub fn wait_until<F>(&self, pred: F) -> ReadGuard<'_, T>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.wait_for(pred, Wait::Forever).unwrap()
+    }
+
+    /// Blocks until the contained value satisfies `pred` or `timeout`
+    /// elapses
+    ///
+    /// The timeout covers the whole wait, including acquiring the read
+    /// lock for each check.
+    ///
+    /// # Returns
+    /// - `Ok(guard)` if `pred` returned `true` in time
+    /// - `Err(TryLockError::TimedOut)` if the timeout elapsed first
+    /// - `Err(TryLockError::Poisoned(_))` if the bag is poisoned
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use ibag::errors::TryLockError;
+    /// use std::time::Duration;
+    ///
+    /// let ready = iBag::new(false);
+    /// let result = ready.wait_until_timeout(Duration::from_millis(10), |ready| *ready);
+    /// assert_eq!(result.err(), Some(TryLockError::TimedOut));
+    ///
+    /// ready.with(|ready| *ready = true);
+    /// assert!(ready.wait_until_timeout(Duration::from_millis(10), |ready| *ready).is_ok());
+    /// ```
+    pub fn wait_until_timeout<F>(&self, timeout: Duration, pred: F) -> Result<ReadGuard<'_, T>, TryLockError>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.wait_for(pred, Wait::timeout(timeout))
+    }
+
+    fn wait_for<F>(&self, mut pred: F, wait: Wait) -> Result<ReadGuard<'_, T>, TryLockError>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        loop {
+            let guard = self.acquire_read(wait)?;
+            // Writers are excluded while the read lock is held, so any
+            // write after the check moves the version past `seen`.
+            let seen = self.version();
+            if pred(&guard) {
+                return Ok(guard);
+            }
+            drop(guard);
+            if self.inner.raw.wait_for_change(seen, wait).is_none() {
+                return Err(TryLockError::TimedOut);
+            }
+        }
+    }
+
+    /// Acquires a read lock without blocking the executor thread
+    ///
+    /// The returned future is runtime-agnostic: a pending task is woken when
+    /// the lock is released, and pending tasks are woken in the order they
+    /// started waiting. Blocking and async callers can share the same bag.
+    ///
+    /// # Panics
+    /// The future panics when it completes on a poisoned bag, like `load()`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    ///
+    /// async fn read(bag: &iBag<i32>) -> i32 {
+    ///     *bag.load_async().await
+    /// }
+    /// ```
+    pub fn load_async(&self) -> ReadFuture<'_, T> {
+        ReadFuture::new(self)
+    }
+
+    /// Acquires a write lock without blocking the executor thread
+    ///
+    /// Pending writers hold back new readers, blocking or async alike.
+    ///
+    /// # Panics
+    /// The future panics when it completes on a poisoned bag, like `write()`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    ///
+    /// async fn reset(bag: &iBag<i32>) {
+    ///     *bag.write_async().await = 0;
+    /// }
+    /// ```
+    pub fn write_async(&self) -> WriteFuture<'_, T> {
+        WriteFuture::new(self)
+    }
+
+    /// Executes a closure with mutable access once the write lock is
+    /// acquired asynchronously
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    ///
+    /// async fn bump(bag: &iBag<i32>) -> i32 {
+    ///     bag.with_async(|val| {
+    ///         *val += 1;
+    ///         *val
+    ///     })
+    ///     .await
+    /// }
+    /// ```
+    pub async fn with_async<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let mut guard = self.write_async().await;
+        f(&mut *guard)
+    }
+
+    /// Executes a closure with read-only access once the read lock is
+    /// acquired asynchronously
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    ///
+    /// async fn get(bag: &iBag<i32>) -> i32 {
+    ///     bag.with_read_async(|val| *val).await
+    /// }
+    /// ```
+    pub async fn with_read_async<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let guard = self.load_async().await;
+        f(&*guard)
+    }
+
+    /// Creates a read-only view of a part of the contained value
+    ///
+    /// The projection keeps a handle to the bag and applies `f` each time it
+    /// is read, so it always sees the current value.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    ///
+    /// struct Config { name: String, retries: u32 }
+    ///
+    /// let bag = iBag::new(Config { name: "svc".into(), retries: 3 });
+    /// let retries = bag.project(|c: &Config| &c.retries);
+    ///
+    /// bag.with(|c| c.retries = 5);
+    /// assert_eq!(*retries.load(), 5);
+    /// assert_eq!(bag.with_read(|c| c.name.len()), 3);
+    /// ```
+    pub fn project<U: ?Sized, F>(&self, f: F) -> Projection<T, U, F>
+    where
+        F: Fn(&T) -> &U,
+    {
+        Projection::new(self.clone(), f)
+    }
+
+    /// Returns a handle that can only read this bag
+    ///
+    /// The handle shares the bag but has no method that writes or that
+    /// returns a writable handle.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// let reader = bag.reader();
+    /// assert_eq!(*reader.load(), 42);
+    /// ```
+    pub fn reader(&self) -> iBagReader<T> {
+        iBagReader::new(self.clone())
+    }
+
+    /// Returns a handle that can only write this bag
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// let writer = bag.writer();
+    /// writer.with(|val| *val = 100);
+    /// assert_eq!(*bag.load(), 100);
+    /// ```
+    pub fn writer(&self) -> iBagWriter<T> {
+        iBagWriter::new(self.clone())
+    }
+
+    /// Creates a weak handle to this bag
+    ///
+    /// A weak handle does not keep the value alive, which breaks reference
+    /// cycles between bags in caches or parent/child links.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// let weak = bag.downgrade();
+    /// assert_eq!(*weak.upgrade().unwrap().load(), 42);
+    ///
+    /// drop(bag);
+    /// assert!(weak.upgrade().is_none());
+    /// ```
+    pub fn downgrade(&self) -> WeakBag<T> {
+        WeakBag {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+
+    /// Returns the number of strong handles to this bag
+    ///
+    /// Reader, writer, watcher and projection handles count as strong
+    /// handles.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// let _other = bag.clone();
+    /// assert_eq!(bag.strong_count(), 2);
+    /// ```
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.inner)
+    }
+
+    /// Returns the number of weak handles to this bag
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(42);
+    /// let _weak = bag.downgrade();
+    /// assert_eq!(bag.weak_count(), 1);
+    /// ```
+    pub fn weak_count(&self) -> usize {
+        Arc::weak_count(&self.inner)
+    }
+
+    /// Returns a mutable reference to the value without locking, if this
+    /// is the only handle to the bag
+    ///
+    /// Like `Arc::get_mut`, this fails while any other strong or weak
+    /// handle exists, including readers, writers and watchers. It counts
+    /// as a write: the version moves on and a bag with history records
+    /// the value it started from.
+    ///
+    /// # Panics
+    /// If the bag is poisoned, like `with`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let mut bag = iBag::new(vec![1]);
+    /// bag.get_mut().unwrap().push(2);
+    ///
+    /// let other = bag.clone();
+    /// assert!(bag.get_mut().is_none());
+    /// drop(other);
+    /// assert_eq!(bag.get_mut().unwrap(), &[1, 2]);
+    /// ```
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        let inner = Arc::get_mut(&mut self.inner)?;
+        if inner.raw.is_poisoned() {
+            panic!("{}", PoisonedBag);
+        }
+        if let Some(history) = &inner.history {
+            history.record(inner.value.get_mut(), inner.raw.version());
+        }
+        inner.raw.bump_version();
+        Some(inner.value.get_mut())
+    }
+
+    /// Returns the value if this is the only strong handle to the bag, or
+    /// the handle back otherwise
+    ///
+    /// Weak handles do not prevent this; they fail to upgrade afterwards.
+    ///
+    /// # Panics
+    /// If the bag is poisoned, like `with`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(String::from("done"));
+    /// let other = bag.clone();
+    /// let bag = bag.try_unwrap().unwrap_err();
+    /// drop(other);
+    /// assert_eq!(bag.try_unwrap().unwrap(), "done");
+    /// ```
+    pub fn try_unwrap(self) -> Result<T, Self> {
+        self.try_unwrap_inner().map(Self::into_value)
+    }
+
+    fn try_unwrap_inner(self) -> Result<Inner<T>, Self> {
+        let mut shared = None;
+        let id = self.inner.raw.listed().then(|| self.inner.raw.id());
+        let inner = registry::moving_out(id, || match Arc::try_unwrap(self.inner) {
+            Ok(inner) => Some(inner),
+            Err(inner) => {
+                shared = Some(inner);
+                None
+            }
+        });
+        inner.ok_or_else(|| iBag { inner: shared.unwrap() })
+    }
+
+    /// Turns the bag into a lock-free, read-only `FrozenBag`, if this is
+    /// the only strong handle
+    ///
+    /// Weak handles do not prevent this; they fail to upgrade afterwards,
+    /// so nothing can write to the value again until it is thawed. The
+    /// history entries are dropped.
+    ///
+    /// # Panics
+    /// If the bag is poisoned, like `with`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(vec![1, 2]);
+    /// let reader = bag.reader();
+    /// let bag = bag.freeze().unwrap_err();
+    /// drop(reader);
+    /// let frozen = bag.freeze().unwrap();
+    /// assert_eq!(frozen.len(), 2);
+    /// ```
+    pub fn freeze(self) -> Result<FrozenBag<T>, Self> {
+        let inner = self.try_unwrap_inner()?;
+        if inner.raw.is_poisoned() {
+            panic!("{}", PoisonedBag);
+        }
+        let history = inner.history.as_ref().map(History::config);
+        Ok(FrozenBag::from_lock(inner.raw, inner.value.into_inner(), history))
+    }
+
+    /// Returns the value if this is the last strong handle to the bag
+    ///
+    /// Like `Arc::into_inner`, when every handle is dropped through this
+    /// method, exactly one of the calls returns the value, even if they
+    /// race on different threads.
+    ///
+    /// # Panics
+    /// If the bag is poisoned, like `with`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use std::thread;
+    ///
+    /// let bag = iBag::new(42);
+    /// let other = bag.clone();
+    /// let elsewhere = thread::spawn(move || other.into_inner());
+    /// let here = bag.into_inner();
+    /// let elsewhere = elsewhere.join().unwrap();
+    /// assert_eq!(here.or(elsewhere), Some(42));
+    /// ```
+    pub fn into_inner(self) -> Option<T> {
+        let id = self.inner.raw.listed().then(|| self.inner.raw.id());
+        registry::moving_out(id, || Arc::into_inner(self.inner)).map(Self::into_value)
+    }
+
+    fn into_value(inner: Inner<T>) -> T {
+        if inner.raw.is_poisoned() {
+            panic!("{}", PoisonedBag);
+        }
+        inner.value.into_inner()
+    }
+
+    /// Returns a mutable reference to the value, first detaching this
+    /// handle with a copy of the value if other handles share the bag
+    ///
+    /// Like `Arc::make_mut`: the other handles keep the original bag, and
+    /// this one moves to a new bag with the same name, policy, observer and
+    /// history limits, but an empty history and a fresh version. Works like
+    /// `get_mut` otherwise.
+    ///
+    /// # Panics
+    /// If the bag is poisoned, like `with`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let mut bag = iBag::new(vec![1]);
+    /// let other = bag.clone();
+    /// bag.make_mut().push(2);
+    /// assert_eq!(*bag.load(), [1, 2]);
+    /// assert_eq!(*other.load(), [1]);
+    /// ```
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn make_mut(&mut self) -> &mut T
+    where
+        T: Clone,
+    {
+        if Arc::get_mut(&mut self.inner).is_none() {
+            let value = self.load().clone();
+            let history = self.inner.history.as_ref().map(History::config);
+            *self = iBag::from_lock(self.inner.raw.detached(), value, history);
+        }
+        self.get_mut().unwrap()
+    }
+
+    /// Runs `f` on the history and the value with the bag write-locked,
+    /// or returns `None` for a bag without history
+    ///
+    /// `f` returns whether it changed the value.
+    fn with_history_locked<F>(&self, f: F) -> Option<bool>
+    where
+        F: FnOnce(&History<T>, &mut T, u64) -> bool,
+    {
+        let history = self.inner.history.as_ref()?;
+        let hold = match self.inner.raw.lock_exclusive(Wait::Forever) {
+            Ok(hold) => hold,
+            Err(_) => unreachable!("blocking acquisition cannot fail"),
+        };
+        let mut guard = self.unrecorded_write_guard(hold);
+        if self.is_poisoned() {
+            guard.mark_unchanged();
+            panic!("{}", PoisonedBag);
+        }
+        let changed = f(history, &mut guard, self.version());
+        if !changed {
+            guard.mark_unchanged();
+        }
+        Some(changed)
+    }
+
+    /// Restores the value from before the last write
+    ///
+    /// Returns `false` if there is nothing to undo or the bag has no
+    /// history. Like a write, the undo bumps the version and notifies
+    /// subscribers.
+    ///
+    /// # Panics
+    /// If the bag is poisoned.
+    pub fn undo(&self) -> bool {
+        self.with_history_locked(|history, value, version| history.undo(value, version))
+            .unwrap_or(false)
+    }
+
+    /// Reapplies the last undone write
+    ///
+    /// Returns `false` if there is nothing to redo. Any new write discards
+    /// the undone states.
+    ///
+    /// # Panics
+    /// If the bag is poisoned.
+    pub fn redo(&self) -> bool {
+        self.with_history_locked(|history, value, version| history.redo(value, version))
+            .unwrap_or(false)
+    }
+
+    /// Names the current value, so `undo_to` can come back to it
+    ///
+    /// Does nothing for a bag without history.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::with_history(vec![1], 100);
+    /// bag.checkpoint("saved");
+    /// bag.with(|v| v.push(2));
+    /// bag.with(|v| v.push(3));
+    /// assert!(bag.undo_to("saved"));
+    /// assert_eq!(*bag.load(), [1]);
+    /// ```
+    pub fn checkpoint(&self, label: impl Into<String>) {
+        let label = label.into();
+        self.with_history_locked(|history, _, version| {
+            history.checkpoint(version, label);
+            false
+        });
+    }
+
+    /// Undoes back to the value named by `checkpoint(label)`
+    ///
+    /// Returns `false`, changing nothing, if the history holds no value
+    /// with that label, for example because it was trimmed.
+    pub fn undo_to(&self, label: &str) -> bool {
+        let mut found = false;
+        self.with_history_locked(|history, value, version| {
+            found = history.undo_to(value, version, label);
+            found
+        });
+        found
+    }
+
+    /// Returns how many earlier values `undo` can go back to
+    ///
+    /// Always zero for a bag without history.
+    pub fn history_len(&self) -> usize {
+        match &self.inner.history {
+            Some(history) => history.len(|| self.version()),
+            None => 0,
+        }
+    }
+}
+
+/// Narrows the error of an acquisition that was allowed to block forever
+fn blocking<G>(result: Result<G, TryLockError>) -> Result<G, PoisonedBag> {
+    match result {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::Poisoned(err)) => Err(err),
+        Err(_) => unreachable!("blocking acquisition cannot fail"),
+    }
+}
+
+/// RAII guard giving shared read access to the value in an iBag
+///
+/// The read lock is released when the guard is dropped.
+pub struct ReadGuard<'a, T: ?Sized> {
+    _hold: SharedHold<'a>,
+    data: NonNull<T>,
+    _marker: PhantomData<&'a T>,
+}
+
+// Unlike the guards of `std::sync::RwLock`, these may be sent to another
+// thread, so async tasks can hold them across `.await` on any executor.
+unsafe impl<T: ?Sized + Sync> Send for ReadGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for ReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.data.as_ref() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// RAII guard giving exclusive write access to the value in an iBag
+///
+/// The write lock is released when the guard is dropped. If the holder
+/// panics the bag becomes poisoned.
+pub struct WriteGuard<'a, T: ?Sized> {
+    hold: ExclusiveHold<'a>,
+    data: NonNull<T>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Send for WriteGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for WriteGuard<'_, T> {}
+
+impl<T: ?Sized> WriteGuard<'_, T> {
+    /// Releases without bumping the version, for writers that did not
+    /// change the value or restored it
+    pub(crate) fn mark_unchanged(&mut self) {
+        self.hold.unchanged();
+    }
+}
+
+impl<T: ?Sized> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.data.as_ref() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.data.as_mut() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for WriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> ReadGuard<'a, T> {
+    /// Narrows the guard to a part of the value, keeping the lock held
+    ///
+    /// This is an associated function so it cannot shadow a method of `T`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::{iBag, ReadGuard};
+    ///
+    /// let bag = iBag::new((1, String::from("name")));
+    /// let name = ReadGuard::map(bag.load(), |pair| pair.1.as_str());
+    /// assert_eq!(&*name, "name");
+    /// ```
+    pub fn map<U: ?Sized, F>(guard: Self, f: F) -> MappedReadGuard<'a, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        let data = NonNull::from(f(unsafe { guard.data.as_ref() }));
+        MappedReadGuard {
+            _hold: guard._hold,
+            data,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Narrows the guard to a part of the value if `f` finds one
+    ///
+    /// Returns the original guard if `f` returns `None`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::{iBag, ReadGuard};
+    ///
+    /// let bag = iBag::new(vec![1, 2, 3]);
+    /// let first = ReadGuard::try_map(bag.load(), |v| v.first()).unwrap();
+    /// assert_eq!(*first, 1);
+    /// drop(first);
+    /// assert!(ReadGuard::try_map(bag.load(), |v| v.get(10)).is_err());
+    /// ```
+    pub fn try_map<U: ?Sized, F>(guard: Self, f: F) -> Result<MappedReadGuard<'a, U>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        match f(unsafe { guard.data.as_ref() }) {
+            Some(data) => {
+                let data = NonNull::from(data);
+                Ok(MappedReadGuard {
+                    _hold: guard._hold,
+                    data,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(guard),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> WriteGuard<'a, T> {
+    /// Narrows the guard to a part of the value, keeping the lock held
+    ///
+    /// This is an associated function so it cannot shadow a method of `T`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::{iBag, WriteGuard};
+    ///
+    /// let bag = iBag::new((1, String::from("name")));
+    /// let mut name = WriteGuard::map(bag.write(), |pair| &mut pair.1);
+    /// name.push('!');
+    /// drop(name);
+    /// assert_eq!(bag.load().1, "name!");
+    /// ```
+    pub fn map<U: ?Sized, F>(mut guard: Self, f: F) -> MappedWriteGuard<'a, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let data = NonNull::from(f(unsafe { guard.data.as_mut() }));
+        MappedWriteGuard {
+            _hold: guard.hold,
+            data,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Narrows the guard to a part of the value if `f` finds one
+    ///
+    /// Returns the original guard if `f` returns `None`.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::{iBag, WriteGuard};
+    ///
+    /// let bag = iBag::new(vec![1, 2, 3]);
+    /// *WriteGuard::try_map(bag.write(), |v| v.last_mut()).unwrap() = 30;
+    /// assert_eq!(*bag.load(), vec![1, 2, 30]);
+    /// ```
+    pub fn try_map<U: ?Sized, F>(guard: Self, f: F) -> Result<MappedWriteGuard<'a, U>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        match f(unsafe { &mut *guard.data.as_ptr() }) {
+            Some(data) => {
+                let data = NonNull::from(data);
+                Ok(MappedWriteGuard {
+                    _hold: guard.hold,
+                    data,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(guard),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> WriteGuard<'a, T> {
+    /// Atomically turns the write guard into a read guard
+    ///
+    /// No other writer can get in between, so the reader sees exactly what
+    /// was written.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::{iBag, WriteGuard};
+    ///
+    /// let bag = iBag::new(1);
+    /// let mut guard = bag.write();
+    /// *guard = 2;
+    /// let guard = WriteGuard::downgrade(guard);
+    /// assert_eq!(*guard, 2);
+    /// assert_eq!(*bag.try_load_now().unwrap(), 2);
+    /// ```
+    pub fn downgrade(guard: Self) -> ReadGuard<'a, T> {
+        ReadGuard {
+            _hold: guard.hold.downgrade(),
+            data: guard.data,
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// RAII guard giving upgradable read access to the value in an iBag
+///
+/// Created by `iBag::upgradable_load()`. Plain readers may hold the bag at
+/// the same time, but writers and other upgradable readers wait until the
+/// guard is dropped, upgraded or downgraded.
+pub struct UpgradableReadGuard<'a, T> {
+    hold: UpgradableHold<'a>,
+    /// Records history once the guard is upgraded
+    bag: &'a iBag<T>,
+    data: NonNull<T>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<T: Send + Sync> Send for UpgradableReadGuard<'_, T> {}
+unsafe impl<T: Send + Sync> Sync for UpgradableReadGuard<'_, T> {}
+
+impl<'a, T> UpgradableReadGuard<'a, T> {
+    /// Atomically turns the guard into a write guard
+    ///
+    /// Waits for the plain readers to leave, and no writer can get in
+    /// between. Under `LockPolicy::WriterPreferring` and `LockPolicy::Fair`
+    /// new readers are held back meanwhile; under
+    /// `LockPolicy::ReaderPreferring` they are not, so a steady stream of
+    /// readers can delay the upgrade indefinitely.
+    pub fn upgrade(guard: Self) -> WriteGuard<'a, T> {
+        match guard.hold.upgrade(Wait::Forever) {
+            Ok(hold) => {
+                guard.bag.record();
+                WriteGuard {
+                    hold,
+                    data: guard.data,
+                    _marker: PhantomData,
+                }
+            }
+            Err(_) => unreachable!("blocking upgrade cannot fail"),
+        }
+    }
+
+    /// Turns the guard into a write guard only if no plain reader holds the
+    /// bag right now
+    ///
+    /// Returns the original guard otherwise.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::{iBag, UpgradableReadGuard};
+    ///
+    /// let bag = iBag::new(1);
+    /// let guard = bag.upgradable_load();
+    /// let reader = bag.try_load_now().unwrap();
+    /// let guard = UpgradableReadGuard::try_upgrade(guard).unwrap_err();
+    /// drop(reader);
+    /// *UpgradableReadGuard::try_upgrade(guard).unwrap() = 2;
+    /// ```
+    pub fn try_upgrade(guard: Self) -> Result<WriteGuard<'a, T>, Self> {
+        let UpgradableReadGuard { hold, bag, data, _marker } = guard;
+        match hold.upgrade(Wait::Never) {
+            Ok(hold) => {
+                bag.record();
+                Ok(WriteGuard {
+                    hold,
+                    data,
+                    _marker: PhantomData,
+                })
+            }
+            Err(hold) => Err(UpgradableReadGuard {
+                hold,
+                bag,
+                data,
+                _marker: PhantomData,
+            }),
+        }
+    }
+
+    /// Atomically turns the guard into a plain read guard, letting writers
+    /// and other upgradable readers queue up behind it
+    pub fn downgrade(guard: Self) -> ReadGuard<'a, T> {
+        ReadGuard {
+            _hold: guard.hold.downgrade(),
+            data: guard.data,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Deref for UpgradableReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.data.as_ref() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for UpgradableReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// RAII guard giving shared read access to a part of the value in an iBag
+///
+/// Created by `ReadGuard::map` and `ReadGuard::try_map`. The read lock on
+/// the whole bag is released when the guard is dropped.
+pub struct MappedReadGuard<'a, T: ?Sized> {
+    _hold: SharedHold<'a>,
+    data: NonNull<T>,
+    _marker: PhantomData<&'a T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Send for MappedReadGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for MappedReadGuard<'_, T> {}
+
+impl<'a, T: ?Sized> MappedReadGuard<'a, T> {
+    /// Narrows the guard further
+    pub fn map<U: ?Sized, F>(guard: Self, f: F) -> MappedReadGuard<'a, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        let data = NonNull::from(f(unsafe { guard.data.as_ref() }));
+        MappedReadGuard {
+            _hold: guard._hold,
+            data,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Narrows the guard further if `f` finds a part, returning the
+    /// original guard otherwise
+    pub fn try_map<U: ?Sized, F>(guard: Self, f: F) -> Result<MappedReadGuard<'a, U>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        match f(unsafe { guard.data.as_ref() }) {
+            Some(data) => {
+                let data = NonNull::from(data);
+                Ok(MappedReadGuard {
+                    _hold: guard._hold,
+                    data,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(guard),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MappedReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.data.as_ref() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MappedReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// RAII guard giving exclusive write access to a part of the value in an
+/// iBag
+///
+/// Created by `WriteGuard::map` and `WriteGuard::try_map`. The write lock on
+/// the whole bag is released when the guard is dropped.
+pub struct MappedWriteGuard<'a, T: ?Sized> {
+    _hold: ExclusiveHold<'a>,
+    data: NonNull<T>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Send for MappedWriteGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for MappedWriteGuard<'_, T> {}
+
+impl<'a, T: ?Sized> MappedWriteGuard<'a, T> {
+    /// Narrows the guard further
+    pub fn map<U: ?Sized, F>(mut guard: Self, f: F) -> MappedWriteGuard<'a, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let data = NonNull::from(f(unsafe { guard.data.as_mut() }));
+        MappedWriteGuard {
+            _hold: guard._hold,
+            data,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Narrows the guard further if `f` finds a part, returning the
+    /// original guard otherwise
+    pub fn try_map<U: ?Sized, F>(guard: Self, f: F) -> Result<MappedWriteGuard<'a, U>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        match f(unsafe { &mut *guard.data.as_ptr() }) {
+            Some(data) => {
+                let data = NonNull::from(data);
+                Ok(MappedWriteGuard {
+                    _hold: guard._hold,
+                    data,
+                    _marker: PhantomData,
+                })
+            }
+            None => Err(guard),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MappedWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.data.as_ref() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MappedWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.data.as_mut() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MappedWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for iBag<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("iBag");
+        if let Some(name) = self.name() {
+            d.field("name", &name);
+        }
+        match self.inner.raw.lock_shared(Wait::Never) {
+            Ok(hold) => {
+                let guard = self.read_guard(hold);
+                d.field("data", &&*guard)
+            }
+            Err(_) => d.field("data", &format_args!("<locked>")),
+        };
+        d.field("poisoned", &self.is_poisoned());
+        d.finish_non_exhaustive()
     }
+}
 
-    /// Acquires a read lock on the contained value
-    ///
-    /// # Safety
-    /// - The returned guard must not outlive the iBag
-    /// - The guard provides thread-safe read-only access
+/// A weak handle to an iBag
+///
+/// Created by `iBag::downgrade()`. It does not keep the value alive; call
+/// `upgrade()` to get a usable bag while a strong handle still exists.
+pub struct WeakBag<T> {
+    inner: Weak<Inner<T>>,
+}
+
+impl<T> WeakBag<T> {
+    /// Creates a weak handle that never upgrades
     ///
     /// # Examples
     /// ```
-    /// use ibag::iBag;
-    /// let bag = iBag::new(42);
-    /// let guard = bag.load();
-    /// assert_eq!(*guard, 42);
+    /// use ibag::WeakBag;
+    /// let weak: WeakBag<i32> = WeakBag::new();
+    /// assert!(weak.upgrade().is_none());
     /// ```
-    pub fn load(&self) -> RwLockReadGuard<T> {
-        self.inner.read().unwrap()
+    pub fn new() -> Self {
+        WeakBag { inner: Weak::new() }
     }
 
-    /// Acquires a write lock on the contained value
-    ///
-    /// # Safety
-    /// - The returned guard must not outlive the iBag
-    /// - The guard provides exclusive mutable access
+    /// Attempts to get a strong handle to the bag
     ///
-    /// # Examples
-    /// ```
-    /// use ibag::iBag;
-    /// let bag = iBag::new(42);
-    /// let mut guard = bag.write();
-    /// *guard = 100;
-    /// ```
-    pub fn write(&self) -> RwLockWriteGuard<T> {
-        self.inner.write().unwrap()
+    /// # Returns
+    /// - `Some(bag)` if the bag is still alive
+    /// - `None` if every strong handle has been dropped
+    pub fn upgrade(&self) -> Option<iBag<T>> {
+        self.inner.upgrade().map(|inner| iBag { inner })
     }
 
-    /// Executes a closure with mutable access to the contained value
-    ///
-    /// # Thread Safety
-    /// - The closure executes with exclusive access
-    /// - Automatically releases the lock when done
-    ///
-    /// # Examples
-    /// ```
-    /// use ibag::iBag;
-    /// let bag = iBag::new(42);
-    /// bag.with(|val| *val = 100);
-    /// ```
-    pub fn with<F, R>(&self, f: F) -> R
-    where
-        F: FnOnce(&mut T) -> R,
-    {
-        let mut guard = self.write();
-        f(&mut *guard)
+    /// Returns the number of strong handles to the bag
+    pub fn strong_count(&self) -> usize {
+        self.inner.strong_count()
     }
 
-    /// Executes a closure with read-only access to the contained value
-    ///
-    /// # Thread Safety
-    /// - The closure executes with shared read access
-    /// - Automatically releases the lock when done
-    ///
-    /// # Examples
-    /// ```
-    /// use ibag::iBag;
-    /// let bag = iBag::new(42);
-    /// let result = bag.with_read(|val| *val);
-    /// assert_eq!(result, 42);
-    /// ```
-    pub fn with_read<F, R>(&self, f: F) -> R
-    where
-        F: FnOnce(&T) -> R,
-    {
-        let guard = self.load();
-        f(&*guard)
+    /// Returns the number of weak handles to the bag, or 0 if no strong
+    /// handle remains
+    pub fn weak_count(&self) -> usize {
+        self.inner.weak_count()
+    }
+}
+
+impl<T> Default for WeakBag<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for WeakBag<T> {
+    fn clone(&self) -> Self {
+        WeakBag {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for WeakBag<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(WeakBag)")
     }
 }
 
@@ -109,17 +1949,16 @@ impl<T: Sized> Clone for iBag<T> {
     }
 }
 
-// Automatic Send+Sync implementation since ArcSwap<T> is already Send+Sync
-unsafe impl<T> Send for iBag<T> {}
-unsafe impl<T> Sync for iBag<T> {}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::time::Instant;
     use std::{thread, vec};
 
     #[test]
+    #[allow(unused_unsafe)]
     fn test_basic_operations() {
         let bag = iBag::new(42);
         assert_eq!(unsafe { *bag.load() }, 42);
@@ -139,6 +1978,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_unsafe)]
     fn test_clone() {
         let bag1 = iBag::new(42);
         let bag2 = bag1.clone();
@@ -150,6 +1990,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_unsafe)]
     fn test_thread_safety() {
         let bag = Arc::new(iBag::new(0));
         let mut handles = vec![];
@@ -200,6 +2041,7 @@ mod tests {
 
 
     #[test]
+    #[allow(unused_unsafe, unused_variables, dead_code, clippy::let_unit_value, clippy::explicit_auto_deref)]
     fn test_thread_safety_with_struct() {
         struct inner {
            pub a: i32,
@@ -236,4 +2078,633 @@ mod tests {
             }
         });
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_poison_is_reported() {
+        let bag = iBag::new(0);
+        let b = bag.clone();
+        let _ = thread::spawn(move || {
+            b.with(|_| panic!("writer panicked"));
+        })
+        .join();
+
+        assert!(bag.is_poisoned());
+        assert_eq!(bag.try_load().err(), Some(PoisonedBag));
+        assert_eq!(bag.try_write().err(), Some(PoisonedBag));
+        assert_eq!(bag.try_with(|v| *v), Err(PoisonedBag));
+        assert_eq!(bag.try_with_read(|v| *v), Err(PoisonedBag));
+    }
+
+    #[test]
+    fn test_recover_poison() {
+        let bag = iBag::new(1);
+        let b = bag.clone();
+        let _ = thread::spawn(move || {
+            b.with(|v| {
+                *v = -1;
+                panic!("writer panicked");
+            });
+        })
+        .join();
+
+        assert!(!bag.recover_poison(|v| *v >= 0));
+        assert!(bag.is_poisoned());
+
+        assert!(bag.recover_poison(|v| {
+            *v = 1;
+            true
+        }));
+        assert_eq!(bag.try_with_read(|v| *v), Ok(1));
+    }
+
+    #[test]
+    fn test_recover_poison_skips_history() {
+        let bag = iBag::with_history(1, 10);
+        let b = bag.clone();
+        let _ = thread::spawn(move || {
+            b.with(|v| {
+                *v = -1;
+                panic!("writer panicked");
+            });
+        })
+        .join();
+        assert_eq!(bag.history_len(), 1);
+
+        assert!(bag.recover_poison(|v| {
+            *v = 1;
+            true
+        }));
+        assert_eq!(bag.history_len(), 1);
+        assert!(bag.recover_poison(|_| true));
+        assert_eq!(bag.history_len(), 2);
+    }
+
+    #[test]
+    fn test_clear_poison() {
+        let bag = iBag::new(1);
+        let b = bag.clone();
+        let _ = thread::spawn(move || {
+            let _guard = b.write();
+            panic!("writer panicked");
+        })
+        .join();
+
+        bag.clear_poison();
+        assert!(!bag.is_poisoned());
+        assert_eq!(*bag.load(), 1);
+    }
+
+    #[test]
+    fn test_try_now() {
+        let bag = iBag::new(0);
+        let read = bag.load();
+        assert!(bag.try_load_now().is_ok());
+        assert_eq!(bag.try_write_now().err(), Some(TryLockError::WouldBlock));
+        drop(read);
+
+        let write = bag.write();
+        assert_eq!(bag.try_load_now().err(), Some(TryLockError::WouldBlock));
+        assert_eq!(bag.try_write_now().err(), Some(TryLockError::WouldBlock));
+        drop(write);
+
+        assert!(bag.try_write_now().is_ok());
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let bag = iBag::new(0);
+        let b = bag.clone();
+        let (held_tx, held_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let holder = thread::spawn(move || {
+            let _guard = b.write();
+            held_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        held_rx.recv().unwrap();
+
+        let timeout = Duration::from_millis(20);
+        assert_eq!(bag.load_timeout(timeout).err(), Some(TryLockError::TimedOut));
+        assert_eq!(bag.write_timeout(timeout).err(), Some(TryLockError::TimedOut));
+        assert_eq!(bag.with_timeout(timeout, |v| *v), Err(TryLockError::TimedOut));
+        assert_eq!(bag.with_read_timeout(timeout, |v| *v), Err(TryLockError::TimedOut));
+
+        release_tx.send(()).unwrap();
+        assert_eq!(bag.with_timeout(Duration::from_secs(5), |v| { *v += 1; *v }), Ok(1));
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn test_timed_out_writer_does_not_block_readers() {
+        let bag = iBag::new(0);
+        let read = bag.load();
+        assert_eq!(
+            bag.write_timeout(Duration::from_millis(10)).err(),
+            Some(TryLockError::TimedOut)
+        );
+        assert!(bag.try_load_now().is_ok());
+        drop(read);
+    }
+
+    #[test]
+    fn test_waiting_writer_blocks_new_readers() {
+        let bag = iBag::new(0);
+        let read = bag.load();
+        let b = bag.clone();
+        let writer = thread::spawn(move || b.with(|v| *v = 1));
+        while bag.try_load_now().is_ok() {
+            thread::yield_now();
+        }
+        drop(read);
+        writer.join().unwrap();
+        assert_eq!(*bag.load(), 1);
+    }
+
+    #[test]
+    fn test_poisoned_try_now() {
+        let bag = iBag::new(0);
+        let b = bag.clone();
+        let _ = thread::spawn(move || b.with(|_| panic!("writer panicked"))).join();
+        assert_eq!(
+            bag.try_load_now().err(),
+            Some(TryLockError::Poisoned(PoisonedBag))
+        );
+        assert!(format!("{:?}", bag).contains("poisoned: true"));
+    }
+
+    #[test]
+    fn test_unwind_safe() {
+        fn assert_unwind_safe<T: UnwindSafe + RefUnwindSafe>() {}
+        assert_unwind_safe::<iBag<i32>>();
+        assert_unwind_safe::<iBag<std::cell::Cell<i32>>>();
+        assert_unwind_safe::<WeakBag<String>>();
+
+        let bag = iBag::new(1);
+        let r = std::panic::catch_unwind(|| bag.with(|_| panic!("writer panicked")));
+        assert!(r.is_err());
+        assert!(bag.is_poisoned());
+    }
+
+    #[test]
+    fn test_version_counts_writes() {
+        let bag = iBag::new(0);
+        assert_eq!(bag.version(), 0);
+        bag.with(|v| *v += 1);
+        *bag.write() += 1;
+        bag.with_read(|v| *v);
+        assert_eq!(bag.version(), 2);
+        assert_eq!(bag.with_read_versioned(|v, val| (v, *val)), (2, 2));
+    }
+
+    #[test]
+    fn test_conflict_does_not_bump_version() {
+        let bag = iBag::new(0);
+        bag.with(|v| *v = 1);
+        assert_eq!(
+            bag.with_if_version(0, |v| *v = 2),
+            Err(VersionConflict { expected: 0, actual: 1 })
+        );
+        assert_eq!(bag.version(), 1);
+        assert_eq!(*bag.load(), 1);
+    }
+
+    #[test]
+    fn test_conflict_is_not_recorded() {
+        let bag = iBag::with_history(0, 10);
+        bag.with(|v| *v = 1);
+        assert!(bag.with_if_version(0, |v| *v = 2).is_err());
+        assert_eq!(bag.history_len(), 1);
+        assert!(bag.with_if_version(1, |v| *v = 2).is_ok());
+        assert_eq!(bag.history_len(), 2);
+    }
+
+    #[test]
+    fn test_optimistic_retry_loop() {
+        let bag = iBag::new(0);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let bag = bag.clone();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        loop {
+                            let (version, value) = bag.with_read_versioned(|v, val| (v, *val));
+                            if bag.with_if_version(version, |val| *val = value + 1).is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*bag.load(), 1600);
+        assert_eq!(bag.version(), 1600);
+    }
+
+    #[test]
+    fn test_submit_from_many_threads() {
+        let bag = iBag::new(Vec::new());
+        thread::scope(|s| {
+            for t in 0..8 {
+                let bag = &bag;
+                s.spawn(move || {
+                    for n in 0..500 {
+                        bag.submit(move |v| v.push((t, n)));
+                    }
+                    bag.flush();
+                });
+            }
+        });
+        let v = bag.load();
+        assert_eq!(v.len(), 4000);
+        // Each thread's mutations are applied in submission order.
+        for t in 0..8 {
+            let ns: Vec<_> = v.iter().filter(|(u, _)| *u == t).map(|(_, n)| *n).collect();
+            assert_eq!(ns, (0..500).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_submissions_join_the_running_batch() {
+        let bag = iBag::new(Vec::new());
+        let (started, running) = mpsc::channel();
+        let (finish, blocked) = mpsc::channel::<()>();
+        let combiner = {
+            let bag = bag.clone();
+            thread::spawn(move || {
+                bag.submit(move |v| {
+                    started.send(()).unwrap();
+                    blocked.recv().unwrap();
+                    v.push(0);
+                })
+            })
+        };
+        running.recv().unwrap();
+        // The combiner holds the write lock; these return right away.
+        for n in 1..=3 {
+            bag.submit(move |v| v.push(n));
+        }
+        bag.submit({
+            let bag = bag.clone();
+            move |v| {
+                v.push(4);
+                // Submitting from a mutation queues behind it.
+                bag.submit(|v| v.push(5));
+            }
+        });
+        finish.send(()).unwrap();
+        bag.flush();
+        combiner.join().unwrap();
+        assert_eq!(*bag.load(), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(bag.version(), 1);
+    }
+
+    #[test]
+    fn test_full_batch_is_one_write() {
+        let bag = iBag::with_history(Vec::new(), 10);
+        let (started, running) = mpsc::channel();
+        let (finish, blocked) = mpsc::channel::<()>();
+        let combiner = {
+            let bag = bag.clone();
+            thread::spawn(move || {
+                bag.submit(move |v| {
+                    started.send(()).unwrap();
+                    blocked.recv().unwrap();
+                    v.push(0);
+                })
+            })
+        };
+        running.recv().unwrap();
+        for n in 1..BATCH_LIMIT {
+            bag.submit(move |v| v.push(n));
+        }
+        finish.send(()).unwrap();
+        bag.flush();
+        // Flushed writes are released, so the version has already moved.
+        assert_eq!((bag.version(), bag.history_len()), (1, 1));
+        combiner.join().unwrap();
+        assert_eq!(*bag.load(), (0..BATCH_LIMIT).collect::<Vec<_>>());
+        assert_eq!((bag.version(), bag.history_len()), (1, 1));
+    }
+
+    #[test]
+    fn test_combiner_releases_the_lock_between_batches() {
+        let bag = iBag::new(Vec::new());
+        let (started, running) = mpsc::channel();
+        let (finish, blocked) = mpsc::channel::<()>();
+        let combiner = {
+            let bag = bag.clone();
+            thread::spawn(move || {
+                bag.submit(move |v| {
+                    started.send(()).unwrap();
+                    blocked.recv().unwrap();
+                    v.push(0);
+                })
+            })
+        };
+        running.recv().unwrap();
+        for n in 1..=100 {
+            bag.submit(move |v| v.push(n));
+        }
+        finish.send(()).unwrap();
+        bag.flush();
+        combiner.join().unwrap();
+        assert_eq!(*bag.load(), (0..=100).collect::<Vec<_>>());
+        // 64 mutations in the first batch, the other 37 in a second one.
+        assert_eq!(bag.version(), 2);
+    }
+
+    #[test]
+    fn test_panicking_submission_poisons() {
+        let bag = iBag::new(0);
+        let b = bag.clone();
+        let result = thread::spawn(move || b.submit(|_| panic!("boom"))).join();
+        assert!(result.is_err());
+        assert!(bag.is_poisoned());
+        bag.flush();
+
+        // Later combiners fail on the poisoned bag without stranding the queue.
+        let b = bag.clone();
+        assert!(thread::spawn(move || b.submit(|v| *v += 1)).join().is_err());
+        bag.flush();
+        bag.clear_poison();
+        bag.submit(|v| *v += 1);
+        assert_eq!(*bag.load(), 1);
+    }
+
+    #[test]
+    fn test_get_mut_needs_the_only_handle() {
+        let mut bag = iBag::new(0);
+        *bag.get_mut().unwrap() += 1;
+        assert_eq!(bag.version(), 1);
+
+        let weak = bag.downgrade();
+        assert!(bag.get_mut().is_none());
+        drop(weak);
+        let reader = bag.reader();
+        assert!(bag.get_mut().is_none());
+        drop(reader);
+        assert_eq!(bag.get_mut(), Some(&mut 1));
+        assert_eq!(bag.version(), 2);
+    }
+
+    #[test]
+    fn test_get_mut_records_history() {
+        let mut bag = iBag::with_history(String::from("a"), 10);
+        bag.get_mut().unwrap().push('b');
+        bag.with(|s| s.push('c'));
+        assert_eq!(bag.history_len(), 2);
+        assert!(bag.undo());
+        assert!(bag.undo());
+        assert_eq!(*bag.load(), "a");
+    }
+
+    #[test]
+    fn test_unwrap() {
+        let bag = iBag::new(vec![1]);
+        let weak = bag.downgrade();
+        let other = bag.clone();
+        let bag = bag.try_unwrap().unwrap_err();
+        assert_eq!(*bag.load(), [1]);
+        assert_eq!(other.into_inner(), None);
+        assert_eq!(bag.try_unwrap().ok(), Some(vec![1]));
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_into_inner_races() {
+        for _ in 0..100 {
+            let bag = iBag::new(String::from("last"));
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let bag = bag.clone();
+                    thread::spawn(move || bag.into_inner())
+                })
+                .collect();
+            let mut found: Vec<_> = handles.into_iter().filter_map(|h| h.join().unwrap()).collect();
+            found.extend(bag.into_inner());
+            assert_eq!(found, ["last"]);
+        }
+    }
+
+    #[test]
+    fn test_make_mut_detaches() {
+        let mut bag = iBag::builder().policy(LockPolicy::Fair).history(5).build(1);
+        bag.with(|v| *v = 2);
+        let other = bag.clone();
+        let mut watcher = other.subscribe();
+        *bag.make_mut() = 3;
+        assert_eq!((*bag.load(), *other.load()), (3, 2));
+        assert!(!watcher.has_changed());
+        assert_eq!(bag.policy(), LockPolicy::Fair);
+        assert_eq!(bag.version(), 1);
+        assert_eq!(other.version(), 1);
+        // The detached bag keeps the history limits but not the entries.
+        assert_eq!(bag.history_len(), 1);
+        assert!(bag.undo());
+        assert_eq!(*bag.load(), 2);
+        assert!(!bag.undo());
+
+        // Once it is the only handle, nothing is copied.
+        let version = bag.version();
+        *bag.make_mut() = 4;
+        assert_eq!(bag.version(), version + 1);
+        assert_eq!(other.strong_count(), 2);
+        watcher.changed_timeout(Duration::ZERO).unwrap_err();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unwrap_poisoned() {
+        let bag = iBag::new(0);
+        let b = bag.clone();
+        let _ = thread::spawn(move || b.with(|_| panic!("boom"))).join();
+        let _ = bag.try_unwrap();
+    }
+
+    #[test]
+    fn test_wait_until_rechecks_after_writes() {
+        let bag = iBag::new(0);
+        let waiter = {
+            let bag = bag.clone();
+            thread::spawn(move || {
+                let mut checks = 0;
+                let value = *bag.wait_until(|v| {
+                    checks += 1;
+                    *v >= 3
+                });
+                (value, checks)
+            })
+        };
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(10));
+            bag.with(|v| *v += 1);
+        }
+        let (value, checks) = waiter.join().unwrap();
+        assert_eq!(value, 3);
+        assert!((1..=4).contains(&checks), "{} checks", checks);
+        // Already satisfied: returns without waiting.
+        assert_eq!(*bag.wait_until(|v| *v == 3), 3);
+    }
+
+    #[test]
+    fn test_wait_until_timeout() {
+        let bag = iBag::new(0);
+        let start = Instant::now();
+        let result = bag.wait_until_timeout(Duration::from_millis(50), |v| *v > 0);
+        assert_eq!(result.err(), Some(TryLockError::TimedOut));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // Writes that do not satisfy the predicate do not end the wait.
+        let b = bag.clone();
+        let writer = thread::spawn(move || {
+            for n in [-1, -2, 5] {
+                thread::sleep(Duration::from_millis(10));
+                b.with(|v| *v = n);
+            }
+        });
+        let guard = bag.wait_until_timeout(Duration::from_secs(10), |v| *v > 0).unwrap();
+        assert_eq!(*guard, 5);
+        drop(guard);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_wait_until_fails_on_poison() {
+        let bag = iBag::new(0);
+        let b = bag.clone();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            b.with(|_| panic!("boom"));
+        });
+        let result = bag.wait_until_timeout(Duration::from_secs(10), |v| *v > 0);
+        assert_eq!(result.err(), Some(TryLockError::Poisoned(PoisonedBag)));
+        assert!(writer.join().is_err());
+    }
+
+    #[test]
+    fn test_weak_breaks_cycles() {
+        struct Node {
+            parent: WeakBag<Node>,
+            children: Vec<iBag<Node>>,
+        }
+
+        let parent = iBag::new(Node { parent: WeakBag::new(), children: vec![] });
+        let child = iBag::new(Node { parent: parent.downgrade(), children: vec![] });
+        parent.with(|p| p.children.push(child.clone()));
+
+        let weak_parent = parent.downgrade();
+        assert_eq!(parent.strong_count(), 1);
+        assert_eq!(parent.weak_count(), 2);
+        assert_eq!(child.strong_count(), 2);
+
+        let up = child.with_read(|c| c.parent.upgrade()).unwrap();
+        assert_eq!(up.with_read(|p| p.children.len()), 1);
+        drop(up);
+
+        drop(parent);
+        assert!(weak_parent.upgrade().is_none());
+        assert_eq!(weak_parent.strong_count(), 0);
+        assert_eq!(child.strong_count(), 1);
+        assert!(child.with_read(|c| c.parent.upgrade().is_none()));
+    }
+
+    #[test]
+    fn test_upgradable_coexists_with_readers() {
+        let bag = iBag::new(1);
+        let upgradable = bag.upgradable_load();
+        let read = bag.try_load_now().unwrap();
+        assert_eq!(*bag.try_load_now().unwrap(), 1);
+        assert_eq!(bag.try_write_now().err(), Some(TryLockError::WouldBlock));
+        assert!(bag.raw().lock_upgradable(Wait::Never).is_err());
+
+        let upgradable = UpgradableReadGuard::try_upgrade(upgradable).unwrap_err();
+        drop(read);
+        *UpgradableReadGuard::try_upgrade(upgradable).unwrap() = 2;
+        assert_eq!(*bag.load(), 2);
+        assert_eq!(bag.version(), 1);
+    }
+
+    #[test]
+    fn test_upgradable_try_and_timeout() {
+        let bag = iBag::new(0);
+        let upgradable = bag.try_upgradable_load().unwrap();
+        assert_eq!(bag.try_upgradable_load_now().err(), Some(TryLockError::WouldBlock));
+        assert_eq!(
+            bag.upgradable_load_timeout(Duration::from_millis(10)).err(),
+            Some(TryLockError::TimedOut)
+        );
+        drop(upgradable);
+        assert!(bag.upgradable_load_timeout(Duration::from_millis(10)).is_ok());
+
+        let b = bag.clone();
+        let _ = thread::spawn(move || b.with(|_| panic!("writer panicked"))).join();
+        assert_eq!(bag.try_upgradable_load().err(), Some(PoisonedBag));
+        assert_eq!(
+            bag.try_upgradable_load_now().err(),
+            Some(TryLockError::Poisoned(PoisonedBag))
+        );
+        // A refused upgradable reader does not keep the lock.
+        assert!(bag.raw().lock_exclusive(Wait::Never).is_ok());
+    }
+
+    #[test]
+    fn test_upgrade_keeps_writers_out() {
+        let bag = iBag::new(0);
+        let upgradable = bag.upgradable_load();
+        let b = bag.clone();
+        let writer = thread::spawn(move || b.with(|v| *v = 10));
+        while bag.raw().waiting() < 1 {
+            thread::yield_now();
+        }
+
+        let mut guard = UpgradableReadGuard::upgrade(upgradable);
+        assert_eq!(*guard, 0);
+        *guard += 1;
+        drop(guard);
+        writer.join().unwrap();
+        assert_eq!(*bag.load(), 10);
+    }
+
+    #[test]
+    fn test_upgrade_waits_for_readers() {
+        let bag = iBag::new(0);
+        let read = bag.load();
+        let b = bag.clone();
+        let upgrader = thread::spawn(move || {
+            let guard = b.upgradable_load();
+            *UpgradableReadGuard::upgrade(guard) = 1;
+        });
+        // Only the upgrade can block here, with a plain reader in.
+        while bag.raw().waiting() < 1 {
+            thread::yield_now();
+        }
+        // The pending upgrade holds back new readers.
+        assert!(bag.try_load_now().is_err());
+        assert_eq!(*read, 0);
+        drop(read);
+        upgrader.join().unwrap();
+        assert_eq!(*bag.load(), 1);
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let bag = iBag::new(0);
+        let mut guard = bag.write();
+        *guard = 1;
+        let read = WriteGuard::downgrade(guard);
+        assert_eq!(bag.version(), 1);
+        assert_eq!(*bag.try_load_now().unwrap(), 1);
+        assert!(bag.try_write_now().is_err());
+        drop(read);
+
+        let read = UpgradableReadGuard::downgrade(bag.upgradable_load());
+        let other = bag.raw().lock_upgradable(Wait::Never).unwrap();
+        assert!(bag.try_write_now().is_err());
+        drop((read, other));
+        assert_eq!(bag.version(), 1);
+        assert!(bag.try_write_now().is_ok());
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Configuring an `iBag` before it is created.
+
+use std::fmt;
+use std::marker::PhantomData;
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+
+use crate::bag::iBag;
+use crate::history::HistoryConfig;
+use crate::lock::{LockPolicy, RawLock};
+#[cfg(feature = "metrics")]
+use crate::metrics::LockObserver;
+
+/// A builder for an iBag with non-default settings
+///
+/// Created by `iBag::builder()`.
+///
+/// # Examples
+/// ```
+/// use ibag::{iBag, LockPolicy};
+///
+/// let bag = iBag::builder().policy(LockPolicy::Fair).build(vec![1, 2]);
+/// assert_eq!(bag.policy(), LockPolicy::Fair);
+/// ```
+pub struct BagBuilder<T> {
+    name: Option<String>,
+    policy: LockPolicy,
+    history: Option<HistoryConfig<T>>,
+    #[cfg(feature = "metrics")]
+    observer: Option<Arc<dyn LockObserver>>,
+    _marker: PhantomData<fn(T) -> T>,
+}
+
+impl<T> BagBuilder<T> {
+    pub(crate) fn new() -> Self {
+        BagBuilder {
+            name: None,
+            policy: LockPolicy::default(),
+            history: None,
+            #[cfg(feature = "metrics")]
+            observer: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Lists the bag under `name` in the registry
+    ///
+    /// Only named bags are listed and track which threads hold them.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the policy that decides who goes first under contention
+    ///
+    /// Defaults to `LockPolicy::WriterPreferring`.
+    pub fn policy(mut self, policy: LockPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Reports every lock event of the bag to `observer`
+    ///
+    /// Requires the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn observer(mut self, observer: Arc<dyn LockObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Keeps up to `capacity` earlier values for `iBag::undo`
+    ///
+    /// Can be combined with `history_budget`; the history then respects
+    /// both limits.
+    pub fn history(mut self, capacity: usize) -> Self
+    where
+        T: Clone,
+    {
+        self.history.get_or_insert_with(HistoryConfig::new).capacity = capacity;
+        self
+    }
+
+    /// Keeps earlier values for `iBag::undo` as long as their sizes, as
+    /// estimated by `size_of`, add up to at most `bytes`
+    ///
+    /// The estimate covers undone values kept for `iBag::redo` as well.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    ///
+    /// let bag = iBag::builder()
+    ///     .history_budget(1024, |doc: &String| doc.capacity())
+    ///     .build(String::new());
+    /// for _ in 0..10 {
+    ///     bag.with(|doc| doc.push_str(&"x".repeat(200)));
+    /// }
+    /// assert!(bag.history_len() < 10);
+    /// ```
+    pub fn history_budget(mut self, bytes: usize, size_of: fn(&T) -> usize) -> Self
+    where
+        T: Clone,
+    {
+        let history = self.history.get_or_insert_with(HistoryConfig::new);
+        history.budget = bytes;
+        history.size_of = size_of;
+        self
+    }
+
+    /// Creates the iBag holding `value`
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn build(self, value: T) -> iBag<T> {
+        let raw = RawLock::new(self.policy).described(self.name, std::any::type_name::<T>());
+        #[cfg(feature = "metrics")]
+        let raw = raw.observed(self.observer);
+        iBag::from_lock(raw, value, self.history)
+    }
+}
+
+impl<T> Default for BagBuilder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for BagBuilder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BagBuilder")
+            .field("name", &self.name)
+            .field("policy", &self.policy)
+            .field("history", &self.history.is_some())
+            .finish_non_exhaustive()
+    }
+}
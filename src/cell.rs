@@ -43,8 +43,16 @@ pub struct CellGuard {
 /// - `guard`: Shared state tracking the current owning thread
 ///
 /// # Thread Safety
-/// While `iCell` implements both `Send` and `Sync`, direct access to the contained
-/// value is only permitted from the owning thread.
+/// `iCell` is `Send` and `Sync` for any `T`, which makes it the way to keep a
+/// value that is neither, such as an `Rc`, inside an `iBag`. This is sound
+/// because only the owning thread may access the value, and ownership can
+/// only move to another thread if `T` is `Send + Sync`. A cell holding any
+/// other value stays with the thread that created it.
+///
+/// Unlike `iBag`, the impls are deliberately not bounded on `T`: bounding
+/// them would make the cell useless for exactly the values it exists for.
+/// Moving the cell only moves a handle. A cell dropped on a foreign thread
+/// panics and leaks the value instead of dropping it there.
 pub struct iCell<T> {
     value: ManuallyDrop<T>,
     guard: Arc<Mutex<CellGuard>>,
@@ -93,6 +101,9 @@ impl<T> iCell<T> {
     /// # Safety
     /// The caller must ensure this is called from the new owning thread.
     ///
+    /// Moving the value to another thread requires `T: Send`, and since the
+    /// previous owner may still hold a reference to it, `T: Sync` as well.
+    ///
     /// # Examples
     /// ```
     /// use std::thread;
@@ -104,7 +115,23 @@ impl<T> iCell<T> {
     ///     // Now this thread owns the cell
     /// });
     /// ```
-    pub fn take_ownership(&self) -> Result<bool, FailTakeOwnership>{
+    ///
+    /// A value that is not `Send` cannot change threads:
+    ///
+    /// ```compile_fail
+    /// use std::rc::Rc;
+    /// use std::thread;
+    /// use ibag::iCell;
+    ///
+    /// let cell = iCell::new(Rc::new(42), false);
+    /// thread::spawn(move || {
+    ///     cell.take_ownership().unwrap();
+    /// });
+    /// ```
+    pub fn take_ownership(&self) -> Result<bool, FailTakeOwnership>
+    where
+        T: Send + Sync,
+    {
         let mut guard = self.guard.lock().unwrap();
         if guard.freeze {
             return Err(FailTakeOwnership);
@@ -213,6 +240,7 @@ impl<T> iCell<T> {
     /// Attempts to get an immutable reference to the wrapped value
     /// - Returns Ok(&T) if called from the owning thread
     /// - Returns Err(InvalidThreadAccess) if called from a non-owning thread
+    ///
     /// Unlike get(), this is a safe operation that doesn't panic
     pub fn try_get(&self) -> Result<&T, InvalidThreadAccess> {
         if self.is_valid() {
@@ -225,6 +253,7 @@ impl<T> iCell<T> {
     /// Attempts to get a mutable reference to the wrapped value
     /// - Returns Ok(&mut T) if called from the owning thread
     /// - Returns Err(InvalidThreadAccess) if called from a non-owning thread
+    ///
     /// Unlike get_mut(), this is a safe operation that doesn't panic
     pub fn try_get_mut(&mut self) -> Result<&mut T, InvalidThreadAccess> {
         if self.is_valid() {
@@ -338,12 +367,15 @@ impl<T: fmt::Debug> fmt::Debug for iCell<T> {
     }
 }
 
-// this type is sync because access can only ever happy from the same thread
-// that created it originally.  All other threads will be able to safely
-// call some basic operations on the reference and they will fail.
+// this type is sync because access can only ever happen from the owning
+// thread.  All other threads will be able to safely call some basic
+// operations on the reference and they will fail.  Ownership only moves
+// through `take_ownership`, which needs `T: Send + Sync`, so a value that
+// is not never leaves the thread that created it.
 unsafe impl<T> Sync for iCell<T> {}
 
-// The entire point of this type is to be Send
+// The entire point of this type is to be Send. Another thread holding the
+// cell cannot read, take or drop the value, so it never leaves its thread.
 #[allow(clippy::non_send_fields_in_send_ty)]
 unsafe impl<T> Send for iCell<T> {}
 
@@ -436,12 +468,12 @@ fn test_rc_sending() {
 }
 
 #[test]
-fn test_rc_sending_take_ownership() {
-    use std::rc::Rc;
+fn test_sending_take_ownership() {
     use std::sync::mpsc::channel;
+    use std::sync::Arc;
     use std::thread;
 
-    let val = iCell::new(Rc::new(true), false);
+    let val = iCell::new(Arc::new(true), false);
     let (tx, rx) = channel();
 
     let sender = thread::spawn(move || {
@@ -466,11 +498,11 @@ fn test_rc_sending_take_ownership() {
 
 #[test]
 fn test_use_after_free() {
-    use std::rc::Rc;
     use std::sync::mpsc::channel;
+    use std::sync::Arc;
     use std::thread;
 
-    let val = iCell::new(Rc::new(true), false);
+    let val = iCell::new(Arc::new(true), false);
     let (tx, rx) = channel();
 
     let sender = thread::spawn(move || {
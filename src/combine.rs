@@ -0,0 +1,137 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Flat combining for `iBag::submit`.
+//!
+//! Submitted mutations go into a queue. The first submitter that finds no
+//! combiner at work becomes the combiner: it takes the write lock and runs
+//! batches from the queue until it finds the queue empty. Everyone else
+//! returns right away, so a burst of submissions costs one lock handoff
+//! per `BATCH_LIMIT` mutations instead of one per mutation. Releasing the
+//! lock in between keeps a steady stream of submissions from shutting out
+//! readers.
+//!
+//! The queue is never left non-empty without a combiner, which is what
+//! lets `flush` simply wait for the applied count to catch up.
+
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+pub(crate) type Op<T> = Box<dyn FnOnce(&mut T) + Send>;
+
+/// Most mutations a combiner runs under one write lock
+pub(crate) const BATCH_LIMIT: usize = 64;
+
+struct Queue<T> {
+    ops: VecDeque<Op<T>>,
+    /// Whether a thread is running the queue
+    combining: bool,
+    submitted: u64,
+    /// Mutations run or discarded, always a prefix of the submitted ones
+    applied: u64,
+}
+
+pub(crate) struct Combiner<T> {
+    queue: Mutex<Queue<T>>,
+    applied: Condvar,
+}
+
+impl<T> Combiner<T> {
+    pub(crate) fn new() -> Self {
+        Combiner {
+            queue: Mutex::new(Queue {
+                ops: VecDeque::new(),
+                combining: false,
+                submitted: 0,
+                applied: 0,
+            }),
+            applied: Condvar::new(),
+        }
+    }
+
+    fn queue(&self) -> MutexGuard<'_, Queue<T>> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queues `op`, returning a `Combining` if the caller has to run the
+    /// queue
+    pub(crate) fn push(&self, op: Op<T>) -> Option<Combining<'_, T>> {
+        let mut queue = self.queue();
+        queue.ops.push_back(op);
+        queue.submitted += 1;
+        if mem::replace(&mut queue.combining, true) {
+            return None;
+        }
+        Some(Combining { combiner: self, ran: 0, taken: 0, done: false })
+    }
+
+    /// Blocks until every mutation queued before the call has been applied
+    /// and the write lock it ran under has been released
+    pub(crate) fn flush(&self) {
+        let mut queue = self.queue();
+        let target = queue.submitted;
+        while queue.applied < target {
+            queue = self.applied.wait(queue).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// The right to run a combiner's queue, held by one thread at a time
+///
+/// Mutations only count as applied once `released` reports that the write
+/// lock they ran under is gone, or the `Combining` is dropped, so `flush`
+/// never returns before their write is visible.
+///
+/// Dropping it mid-way, when a mutation panics or the bag turns out to be
+/// poisoned, discards everything still queued so that no mutation is left
+/// without a combiner.
+pub(crate) struct Combining<'a, T> {
+    combiner: &'a Combiner<T>,
+    /// Mutations run under the current write lock
+    ran: u64,
+    /// Size of the batch being run
+    taken: u64,
+    done: bool,
+}
+
+impl<T> Combining<'_, T> {
+    /// Marks the last batch as run and takes the next one of at most
+    /// `limit` mutations, or gives up the combiner role if the queue is
+    /// empty
+    pub(crate) fn next_batch(&mut self, limit: usize) -> Option<Vec<Op<T>>> {
+        let mut queue = self.combiner.queue();
+        self.ran += mem::take(&mut self.taken);
+        if queue.ops.is_empty() {
+            queue.combining = false;
+            self.done = true;
+            return None;
+        }
+        let len = queue.ops.len().min(limit);
+        let batch: Vec<_> = queue.ops.drain(..len).collect();
+        self.taken = batch.len() as u64;
+        Some(batch)
+    }
+
+    /// Counts what ran as applied, once the write lock has been released
+    pub(crate) fn released(&mut self) {
+        let mut queue = self.combiner.queue();
+        queue.applied += mem::take(&mut self.ran);
+        self.combiner.applied.notify_all();
+    }
+}
+
+impl<T> Drop for Combining<'_, T> {
+    fn drop(&mut self) {
+        let mut queue = self.combiner.queue();
+        let discarded = if self.done { VecDeque::new() } else { mem::take(&mut queue.ops) };
+        queue.applied += self.ran + self.taken + discarded.len() as u64;
+        if !self.done {
+            queue.combining = false;
+        }
+        self.combiner.applied.notify_all();
+        drop(queue);
+        // Dropping a closure may run arbitrary code; not under the mutex.
+        drop(discarded);
+    }
+}
@@ -25,4 +25,82 @@ impl fmt::Display for FailTakeOwnership {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "failed to take ownership of value")
     }
-}
\ No newline at end of file
+}
+
+/// Returned when a bag's lock was poisoned by a panicking writer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoisonedBag;
+
+impl fmt::Display for PoisonedBag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bag poisoned by a panic while it was being written")
+    }
+}
+
+impl error::Error for PoisonedBag {}
+
+
+/// Returned when a bag's lock could not be acquired without waiting longer
+/// than the caller allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryLockError {
+    /// The lock is contended and the caller asked not to wait.
+    WouldBlock,
+    /// The lock was still contended when the timeout elapsed.
+    TimedOut,
+    /// The lock was acquired but the bag is poisoned.
+    Poisoned(PoisonedBag),
+}
+
+impl From<PoisonedBag> for TryLockError {
+    fn from(err: PoisonedBag) -> Self {
+        TryLockError::Poisoned(err)
+    }
+}
+
+impl fmt::Display for TryLockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryLockError::WouldBlock => write!(f, "bag lock acquisition would block"),
+            TryLockError::TimedOut => write!(f, "timed out waiting for bag lock"),
+            TryLockError::Poisoned(err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
+impl error::Error for TryLockError {}
+
+
+/// Returned when a wait for a bag to change times out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeout;
+
+impl fmt::Display for WaitTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "timed out waiting for bag to change")
+    }
+}
+
+impl error::Error for WaitTimeout {}
+
+
+/// Returned when a conditional write finds that another writer got in first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionConflict {
+    /// The version the caller based its update on.
+    pub expected: u64,
+    /// The version the bag was at when the write was attempted.
+    pub actual: u64,
+}
+
+impl fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "bag changed concurrently: expected version {}, found {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl error::Error for VersionConflict {}
@@ -0,0 +1,208 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Bags that will never be written again.
+//!
+//! `iBag::freeze` moves the value out of a uniquely owned bag into a
+//! `FrozenBag`, a shared handle that dereferences to the value without a
+//! lock. The bag's lock goes along unused, so `FrozenBag::thaw` can bring
+//! the bag back with its name, policy, version and observer.
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::bag::iBag;
+use crate::history::HistoryConfig;
+use crate::lock::{LockPolicy, RawLock};
+
+/// A read-only, lock-free handle to a value that was frozen
+///
+/// Cloning is as cheap as cloning an `Arc`, and reading is a plain
+/// dereference.
+///
+/// # Examples
+/// ```
+/// use ibag::{iBag, FrozenBag};
+///
+/// let config = iBag::new(vec![1, 2]);
+/// config.with(|c| c.push(3));
+/// let config: FrozenBag<Vec<i32>> = config.freeze().unwrap();
+///
+/// let copy = config.clone();
+/// assert_eq!(copy.len(), 3);
+///
+/// drop(copy);
+/// let config = config.thaw().unwrap();
+/// config.with(|c| c.push(4));
+/// ```
+pub struct FrozenBag<T> {
+    inner: Arc<Frozen<T>>,
+}
+
+struct Frozen<T> {
+    value: T,
+    /// The lock of the bag the value was frozen from, kept for `thaw`
+    raw: RawLock,
+    history: Option<HistoryConfig<T>>,
+}
+
+impl<T> FrozenBag<T> {
+    /// Creates a frozen bag holding `value`
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::FrozenBag;
+    /// let bag = FrozenBag::new(42);
+    /// assert_eq!(*bag, 42);
+    /// ```
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn new(value: T) -> Self {
+        let raw = RawLock::new(LockPolicy::default()).described(None, std::any::type_name::<T>());
+        Self::from_lock(raw, value, None)
+    }
+
+    pub(crate) fn from_lock(raw: RawLock, value: T, history: Option<HistoryConfig<T>>) -> Self {
+        FrozenBag {
+            inner: Arc::new(Frozen { value, raw, history }),
+        }
+    }
+
+    /// Returns a writable bag again, if this is the only handle
+    ///
+    /// The bag gets back its name, policy, observer, version and history
+    /// limits. The history itself starts empty.
+    ///
+    /// # Returns
+    /// - `Ok(bag)` if no other handle shares the frozen value
+    /// - `Err(self)` otherwise
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::FrozenBag;
+    /// let frozen = FrozenBag::new(1);
+    /// let copy = frozen.clone();
+    /// let frozen = frozen.thaw().unwrap_err();
+    /// drop(copy);
+    /// let bag = frozen.thaw().unwrap();
+    /// bag.with(|v| *v += 1);
+    /// ```
+    pub fn thaw(self) -> Result<iBag<T>, Self> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(frozen) => Ok(iBag::from_lock(frozen.raw, frozen.value, frozen.history)),
+            Err(inner) => Err(FrozenBag { inner }),
+        }
+    }
+
+    /// Returns the name the bag was created with, if any
+    pub fn name(&self) -> Option<&str> {
+        self.inner.raw.name()
+    }
+
+    /// Returns the number of handles to the frozen value
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.inner)
+    }
+
+    /// Returns `true` if both handles share the same value
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        Arc::ptr_eq(&this.inner, &other.inner)
+    }
+}
+
+impl<T> Deref for FrozenBag<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner.value
+    }
+}
+
+impl<T> AsRef<T> for FrozenBag<T> {
+    fn as_ref(&self) -> &T {
+        &self.inner.value
+    }
+}
+
+impl<T> Clone for FrozenBag<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> From<T> for FrozenBag<T> {
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for FrozenBag<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("FrozenBag");
+        if let Some(name) = self.name() {
+            debug.field("name", &name);
+        }
+        debug.field("data", &self.inner.value).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry;
+    use std::thread;
+
+    #[test]
+    fn test_freeze_needs_the_only_handle() {
+        let bag = iBag::new(1);
+        let other = bag.clone();
+        let bag = bag.freeze().unwrap_err();
+        drop(other);
+
+        // Weak handles cannot write once the bag is frozen.
+        let weak = bag.downgrade();
+        let frozen = bag.freeze().unwrap();
+        assert!(weak.upgrade().is_none());
+        assert_eq!(*frozen, 1);
+    }
+
+    #[test]
+    fn test_shared_reads() {
+        let frozen = FrozenBag::new(vec![1, 2, 3]);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                let frozen = frozen.clone();
+                s.spawn(move || assert_eq!(frozen.iter().sum::<i32>(), 6));
+            }
+        });
+        assert_eq!(frozen.strong_count(), 1);
+        assert!(FrozenBag::ptr_eq(&frozen, &frozen.clone()));
+        assert!(!FrozenBag::ptr_eq(&frozen, &FrozenBag::new(vec![1, 2, 3])));
+    }
+
+    #[test]
+    fn test_thaw_restores_the_bag() {
+        let bag = iBag::builder()
+            .name("frozen-thaw")
+            .policy(LockPolicy::Fair)
+            .history(3)
+            .build(0);
+        bag.with(|v| *v = 1);
+        let frozen = bag.freeze().unwrap();
+        assert_eq!(frozen.name(), Some("frozen-thaw"));
+        assert!(format!("{:?}", frozen).contains("frozen-thaw"));
+        let listed = |name| registry::bags().iter().any(|info| info.name.as_deref() == Some(name));
+        assert!(!listed("frozen-thaw"));
+
+        let bag = frozen.thaw().unwrap();
+        assert!(listed("frozen-thaw"));
+        assert_eq!((bag.name(), bag.policy(), bag.version()), (Some("frozen-thaw"), LockPolicy::Fair, 1));
+        assert!(!bag.undo());
+        bag.with(|v| *v = 2);
+        assert!(bag.undo());
+        assert_eq!(*bag.load(), 1);
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Futures returned by the async accessors of `iBag`.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::bag::{iBag, ReadGuard, WriteGuard};
+use crate::lock::AsyncWait;
+
+/// Future returned by `iBag::load_async()`
+///
+/// Resolves to a read guard once no writer holds or waits for the lock.
+#[must_use = "futures do nothing unless polled"]
+pub struct ReadFuture<'a, T> {
+    bag: &'a iBag<T>,
+    wait: AsyncWait<'a>,
+}
+
+impl<'a, T> ReadFuture<'a, T> {
+    pub(crate) fn new(bag: &'a iBag<T>) -> Self {
+        ReadFuture {
+            bag,
+            wait: AsyncWait::new(bag.raw()),
+        }
+    }
+}
+
+impl<'a, T> Future for ReadFuture<'a, T> {
+    type Output = ReadGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.wait
+            .poll_shared(cx)
+            .map(|hold| this.bag.checked_read(hold).unwrap())
+    }
+}
+
+impl<T> fmt::Debug for ReadFuture<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReadFuture").finish_non_exhaustive()
+    }
+}
+
+/// Future returned by `iBag::write_async()`
+///
+/// Resolves to a write guard once the lock is free.
+#[must_use = "futures do nothing unless polled"]
+pub struct WriteFuture<'a, T> {
+    bag: &'a iBag<T>,
+    wait: AsyncWait<'a>,
+}
+
+impl<'a, T> WriteFuture<'a, T> {
+    pub(crate) fn new(bag: &'a iBag<T>) -> Self {
+        WriteFuture {
+            bag,
+            wait: AsyncWait::new(bag.raw()),
+        }
+    }
+}
+
+impl<'a, T> Future for WriteFuture<'a, T> {
+    type Output = WriteGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.wait
+            .poll_exclusive(cx)
+            .map(|hold| this.bag.checked_write(hold).unwrap())
+    }
+}
+
+impl<T> fmt::Debug for WriteFuture<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WriteFuture").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,337 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Read-only and write-only handles to an `iBag`.
+//!
+//! Both handles share the bag's allocation but expose only one side of its
+//! API. Neither gives out the underlying `iBag`, so a reader handed to a
+//! plugin can never be turned back into something that writes.
+
+#![allow(non_camel_case_types)]
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::bag::{iBag, ReadGuard, WriteGuard};
+use crate::errors::{PoisonedBag, TryLockError, VersionConflict};
+use crate::future::{ReadFuture, WriteFuture};
+use crate::project::Projection;
+use crate::watch::Watcher;
+
+/// A handle that can only read an iBag
+///
+/// Created by `iBag::reader()` or `iBagWriter::reader()`.
+///
+/// # Examples
+/// ```
+/// use ibag::iBag;
+///
+/// let bag = iBag::new(42);
+/// let reader = bag.reader();
+/// bag.with(|val| *val = 100);
+/// assert_eq!(reader.with_read(|val| *val), 100);
+/// ```
+///
+/// A reader has no way to write:
+/// ```compile_fail
+/// use ibag::iBag;
+///
+/// let reader = iBag::new(42).reader();
+/// reader.with(|val| *val = 100);
+/// ```
+pub struct iBagReader<T> {
+    bag: iBag<T>,
+}
+
+impl<T> iBagReader<T> {
+    pub(crate) fn new(bag: iBag<T>) -> Self {
+        iBagReader { bag }
+    }
+
+    /// Acquires a read lock, see `iBag::load`
+    pub fn load(&self) -> ReadGuard<'_, T> {
+        self.bag.load()
+    }
+
+    /// Executes a closure with read-only access, see `iBag::with_read`
+    pub fn with_read<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.bag.with_read(f)
+    }
+
+    /// Acquires a read lock without panicking on poison, see `iBag::try_load`
+    pub fn try_load(&self) -> Result<ReadGuard<'_, T>, PoisonedBag> {
+        self.bag.try_load()
+    }
+
+    /// Executes a closure without panicking on poison, see
+    /// `iBag::try_with_read`
+    pub fn try_with_read<F, R>(&self, f: F) -> Result<R, PoisonedBag>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.bag.try_with_read(f)
+    }
+
+    /// Acquires a read lock only if available now, see `iBag::try_load_now`
+    pub fn try_load_now(&self) -> Result<ReadGuard<'_, T>, TryLockError> {
+        self.bag.try_load_now()
+    }
+
+    /// Acquires a read lock, waiting at most `timeout`, see
+    /// `iBag::load_timeout`
+    pub fn load_timeout(&self, timeout: Duration) -> Result<ReadGuard<'_, T>, TryLockError> {
+        self.bag.load_timeout(timeout)
+    }
+
+    /// Executes a closure, waiting at most `timeout` for the read lock, see
+    /// `iBag::with_read_timeout`
+    pub fn with_read_timeout<F, R>(&self, timeout: Duration, f: F) -> Result<R, TryLockError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.bag.with_read_timeout(timeout, f)
+    }
+
+    /// Acquires a read lock asynchronously, see `iBag::load_async`
+    pub fn load_async(&self) -> ReadFuture<'_, T> {
+        self.bag.load_async()
+    }
+
+    /// Executes a closure once the read lock is acquired asynchronously, see
+    /// `iBag::with_read_async`
+    pub async fn with_read_async<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.bag.with_read_async(f).await
+    }
+
+    /// Returns the current version, see `iBag::version`
+    pub fn version(&self) -> u64 {
+        self.bag.version()
+    }
+
+    /// Executes a closure with the value and its version, see
+    /// `iBag::with_read_versioned`
+    pub fn with_read_versioned<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(u64, &T) -> R,
+    {
+        self.bag.with_read_versioned(f)
+    }
+
+    /// Subscribes to changes, see `iBag::subscribe`
+    pub fn subscribe(&self) -> Watcher<T> {
+        self.bag.subscribe()
+    }
+
+    /// Creates a read-only view of a part of the value, see `iBag::project`
+    pub fn project<U: ?Sized, F>(&self, f: F) -> Projection<T, U, F>
+    where
+        F: Fn(&T) -> &U,
+    {
+        self.bag.project(f)
+    }
+
+    /// Returns `true` if a writer panicked while holding the lock
+    pub fn is_poisoned(&self) -> bool {
+        self.bag.is_poisoned()
+    }
+}
+
+impl<T> Clone for iBagReader<T> {
+    fn clone(&self) -> Self {
+        iBagReader {
+            bag: self.bag.clone(),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for iBagReader<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("iBagReader").field(&self.bag).finish()
+    }
+}
+
+/// A handle that can only write an iBag
+///
+/// Created by `iBag::writer()`. A writer has no accessors that only read:
+/// no `load`, `with_read` or `watch`. It can still read what it writes,
+/// through the `&mut T` of a write or a write guard turned into a read
+/// guard with `WriteGuard::downgrade`, and it can give out readers with
+/// `reader()`. The split states what a handle is for; it does not keep a
+/// writer from reading.
+///
+/// # Examples
+/// ```
+/// use ibag::iBag;
+///
+/// let bag = iBag::new(42);
+/// let writer = bag.writer();
+/// writer.with(|val| *val = 100);
+/// assert_eq!(*bag.load(), 100);
+/// ```
+///
+/// A writer has no read accessors:
+/// ```compile_fail
+/// use ibag::iBag;
+///
+/// let writer = iBag::new(42).writer();
+/// writer.load();
+/// ```
+pub struct iBagWriter<T> {
+    bag: iBag<T>,
+}
+
+impl<T> iBagWriter<T> {
+    pub(crate) fn new(bag: iBag<T>) -> Self {
+        iBagWriter { bag }
+    }
+
+    /// Returns a read-only handle to the same bag
+    pub fn reader(&self) -> iBagReader<T> {
+        iBagReader::new(self.bag.clone())
+    }
+
+    /// Acquires a write lock, see `iBag::write`
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        self.bag.write()
+    }
+
+    /// Executes a closure with mutable access, see `iBag::with`
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        self.bag.with(f)
+    }
+
+    /// Acquires a write lock without panicking on poison, see
+    /// `iBag::try_write`
+    pub fn try_write(&self) -> Result<WriteGuard<'_, T>, PoisonedBag> {
+        self.bag.try_write()
+    }
+
+    /// Executes a closure without panicking on poison, see `iBag::try_with`
+    pub fn try_with<F, R>(&self, f: F) -> Result<R, PoisonedBag>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        self.bag.try_with(f)
+    }
+
+    /// Acquires a write lock only if available now, see
+    /// `iBag::try_write_now`
+    pub fn try_write_now(&self) -> Result<WriteGuard<'_, T>, TryLockError> {
+        self.bag.try_write_now()
+    }
+
+    /// Acquires a write lock, waiting at most `timeout`, see
+    /// `iBag::write_timeout`
+    pub fn write_timeout(&self, timeout: Duration) -> Result<WriteGuard<'_, T>, TryLockError> {
+        self.bag.write_timeout(timeout)
+    }
+
+    /// Executes a closure, waiting at most `timeout` for the write lock, see
+    /// `iBag::with_timeout`
+    pub fn with_timeout<F, R>(&self, timeout: Duration, f: F) -> Result<R, TryLockError>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        self.bag.with_timeout(timeout, f)
+    }
+
+    /// Acquires a write lock asynchronously, see `iBag::write_async`
+    pub fn write_async(&self) -> WriteFuture<'_, T> {
+        self.bag.write_async()
+    }
+
+    /// Executes a closure once the write lock is acquired asynchronously,
+    /// see `iBag::with_async`
+    pub async fn with_async<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        self.bag.with_async(f).await
+    }
+
+    /// Returns the current version, see `iBag::version`
+    pub fn version(&self) -> u64 {
+        self.bag.version()
+    }
+
+    /// Executes a closure only if the bag is at the `expected` version, see
+    /// `iBag::with_if_version`
+    pub fn with_if_version<F, R>(&self, expected: u64, f: F) -> Result<R, VersionConflict>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        self.bag.with_if_version(expected, f)
+    }
+
+    /// Returns `true` if a writer panicked while holding the lock
+    pub fn is_poisoned(&self) -> bool {
+        self.bag.is_poisoned()
+    }
+
+    /// Clears the poisoned state, see `iBag::clear_poison`
+    pub fn clear_poison(&self) {
+        self.bag.clear_poison()
+    }
+
+    /// Repairs a possibly poisoned value, see `iBag::recover_poison`
+    pub fn recover_poison<F>(&self, f: F) -> bool
+    where
+        F: FnOnce(&mut T) -> bool,
+    {
+        self.bag.recover_poison(f)
+    }
+}
+
+impl<T> Clone for iBagWriter<T> {
+    fn clone(&self) -> Self {
+        iBagWriter {
+            bag: self.bag.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for iBagWriter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("iBagWriter").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_handles_share_value() {
+        let bag = iBag::new(vec![1]);
+        let reader = bag.reader();
+        let writer = bag.writer();
+        writer.with(|v| v.push(2));
+        assert_eq!(*reader.load(), vec![1, 2]);
+        assert_eq!(writer.reader().with_read(|v| v.len()), 2);
+        assert_eq!(reader.version(), writer.version());
+    }
+
+    #[test]
+    fn test_reader_watches_writer() {
+        let bag = iBag::new(0);
+        let reader = bag.reader();
+        let writer = bag.writer();
+        drop(bag);
+
+        let mut watcher = reader.subscribe();
+        let handle = thread::spawn(move || writer.with(|v| *v = 1));
+        watcher.changed();
+        assert_eq!(*watcher.borrow_latest(), 1);
+        handle.join().unwrap();
+    }
+}
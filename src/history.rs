@@ -0,0 +1,209 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Undo and redo snapshots for bags created with history.
+//!
+//! A writer takes a snapshot of the value when it gets exclusive access,
+//! or when an upgradable reader upgrades, before it can change anything.
+//! Upgradable reads that never upgrade cost no snapshot. The snapshot stays
+//! pending until a later history operation, which keeps it only if the
+//! bag's version moved on, so writers that released without a change (a
+//! rolled back transaction, a version conflict) leave no entry behind.
+//!
+//! Everything except `len` runs while the bag excludes other writers; the
+//! mutex keeps `len` consistent for readers.
+
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// How a bag keeps its history, set through `BagBuilder`
+pub(crate) struct HistoryConfig<T> {
+    pub(crate) capacity: usize,
+    pub(crate) budget: usize,
+    pub(crate) clone: fn(&T) -> T,
+    pub(crate) size_of: fn(&T) -> usize,
+}
+
+impl<T> Clone for HistoryConfig<T> {
+    fn clone(&self) -> Self {
+        HistoryConfig {
+            capacity: self.capacity,
+            budget: self.budget,
+            clone: self.clone,
+            size_of: self.size_of,
+        }
+    }
+}
+
+impl<T: Clone> HistoryConfig<T> {
+    pub(crate) fn new() -> Self {
+        HistoryConfig {
+            capacity: usize::MAX,
+            budget: usize::MAX,
+            clone: T::clone,
+            size_of: |_| mem::size_of::<T>(),
+        }
+    }
+}
+
+/// A past or undone state of the value
+struct Entry<T> {
+    value: T,
+    label: Option<Box<str>>,
+    size: usize,
+}
+
+struct Stacks<T> {
+    /// Older states, newest last
+    undo: VecDeque<Entry<T>>,
+    /// Undone states, the next one to redo last
+    redo: VecDeque<Entry<T>>,
+    /// Approximate size of all entries
+    bytes: usize,
+    /// Label of the current state, given by `checkpoint`
+    label: Option<Box<str>>,
+    /// Snapshot taken by the current or last writer, and the version it saw
+    pending: Option<(T, u64)>,
+}
+
+pub(crate) struct History<T> {
+    config: HistoryConfig<T>,
+    stacks: Mutex<Stacks<T>>,
+}
+
+impl<T> History<T> {
+    pub(crate) fn new(config: HistoryConfig<T>) -> Self {
+        History {
+            config,
+            stacks: Mutex::new(Stacks {
+                undo: VecDeque::new(),
+                redo: VecDeque::new(),
+                bytes: 0,
+                label: None,
+                pending: None,
+            }),
+        }
+    }
+
+    pub(crate) fn config(&self) -> HistoryConfig<T> {
+        self.config.clone()
+    }
+
+    fn stacks(&self) -> MutexGuard<'_, Stacks<T>> {
+        // A panicking size function can at worst skew the byte count.
+        self.stacks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn entry(&self, value: T, label: Option<Box<str>>) -> Entry<T> {
+        let size = (self.config.size_of)(&value);
+        Entry { value, label, size }
+    }
+
+    /// Takes the snapshot for a writer that just got access at `version`
+    pub(crate) fn record(&self, value: &T, version: u64) {
+        let snapshot = (self.config.clone)(value);
+        let mut stacks = self.settled(version);
+        stacks.pending = Some((snapshot, version));
+    }
+
+    /// Commits the pending snapshot if the value changed since it was taken
+    ///
+    /// A snapshot of an unchanged value stays pending, since its writer may
+    /// still hold the bag.
+    fn settle(&self, stacks: &mut Stacks<T>, version: u64) {
+        match stacks.pending.take() {
+            Some((value, seen)) if seen != version => {
+                let label = stacks.label.take();
+                let entry = self.entry(value, label);
+                for undone in stacks.redo.drain(..) {
+                    stacks.bytes -= undone.size;
+                }
+                stacks.bytes += entry.size;
+                stacks.undo.push_back(entry);
+                self.trim(stacks);
+            }
+            pending => stacks.pending = pending,
+        }
+    }
+
+    /// Locks and settles the stacks for an operation that holds the bag
+    /// exclusively
+    ///
+    /// No writer can be running, so a snapshot that is still pending was
+    /// taken by a writer that changed nothing.
+    fn settled(&self, version: u64) -> MutexGuard<'_, Stacks<T>> {
+        let mut stacks = self.stacks();
+        self.settle(&mut stacks, version);
+        stacks.pending = None;
+        stacks
+    }
+
+    /// Drops the oldest entries until the history fits its limits
+    fn trim(&self, stacks: &mut Stacks<T>) {
+        while stacks.undo.len() + stacks.redo.len() > self.config.capacity
+            || stacks.bytes > self.config.budget
+        {
+            let Some(entry) = stacks.undo.pop_front().or_else(|| stacks.redo.pop_front()) else {
+                break;
+            };
+            stacks.bytes -= entry.size;
+        }
+    }
+
+    /// Moves one state back or forward, returning `false` if there is none
+    fn step(&self, stacks: &mut Stacks<T>, value: &mut T, forward: bool) -> bool {
+        let next = if forward { stacks.redo.pop_back() } else { stacks.undo.pop_back() };
+        let Some(next) = next else {
+            return false;
+        };
+        stacks.bytes -= next.size;
+        let current = mem::replace(value, next.value);
+        let label = mem::replace(&mut stacks.label, next.label);
+        let current = self.entry(current, label);
+        stacks.bytes += current.size;
+        if forward {
+            stacks.undo.push_back(current);
+        } else {
+            stacks.redo.push_back(current);
+        }
+        self.trim(stacks);
+        true
+    }
+
+    pub(crate) fn undo(&self, value: &mut T, version: u64) -> bool {
+        self.step(&mut self.settled(version), value, false)
+    }
+
+    pub(crate) fn redo(&self, value: &mut T, version: u64) -> bool {
+        self.step(&mut self.settled(version), value, true)
+    }
+
+    /// Undoes until the current state carries `label`
+    ///
+    /// Returns `false`, changing nothing, if no state in the history has it.
+    pub(crate) fn undo_to(&self, value: &mut T, version: u64, label: &str) -> bool {
+        let mut stacks = self.settled(version);
+        let found = stacks.label.as_deref() == Some(label)
+            || stacks.undo.iter().any(|entry| entry.label.as_deref() == Some(label));
+        if found {
+            while stacks.label.as_deref() != Some(label) && self.step(&mut stacks, value, false) {}
+        }
+        found
+    }
+
+    /// Names the current state
+    pub(crate) fn checkpoint(&self, version: u64, label: String) {
+        self.settled(version).label = Some(label.into_boxed_str());
+    }
+
+    /// Returns the number of states `undo` can go back to
+    ///
+    /// Called without holding the bag, so the version is read only once no
+    /// writer can take a new snapshot.
+    pub(crate) fn len(&self, version: impl FnOnce() -> u64) -> usize {
+        let mut stacks = self.stacks();
+        self.settle(&mut stacks, version());
+        stacks.undo.len()
+    }
+}
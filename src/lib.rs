@@ -3,8 +3,40 @@
 
 pub mod errors;
 pub mod bag;
+pub mod builder;
 pub mod cell;
+pub mod frozen;
+pub mod future;
+pub mod handle;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod persist;
+pub mod project;
+pub mod registry;
 pub mod sendable;
+pub mod seq;
+pub mod snapshot;
+#[cfg(feature = "serde")]
+mod serde_impls;
+pub mod swap;
+pub mod txn;
+pub mod watch;
+mod combine;
+mod history;
+mod lock;
 
-pub use bag::iBag;
+pub use bag::{iBag, MappedReadGuard, MappedWriteGuard, ReadGuard, UpgradableReadGuard, WeakBag, WriteGuard};
+pub use builder::BagBuilder;
 pub use cell::iCell;
+pub use frozen::FrozenBag;
+pub use handle::{iBagReader, iBagWriter};
+pub use lock::LockPolicy;
+#[cfg(feature = "metrics")]
+pub use metrics::{LockObserver, LockStats};
+pub use persist::PersistentBag;
+pub use project::Projection;
+pub use seq::SeqBag;
+pub use snapshot::snapshot;
+pub use swap::SwapBag;
+pub use txn::txn;
+pub use watch::Watcher;
@@ -0,0 +1,1011 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! The reader-writer lock behind `iBag`.
+//!
+//! `std::sync::RwLock` cannot wait with a deadline, so the bag uses this
+//! small lock built from a `Mutex`-protected state and a `Condvar`. Who
+//! goes first under contention is decided by a `LockPolicy` fixed when the
+//! lock is created.
+//!
+//! Every release of exclusive access bumps a version counter and wakes
+//! threads waiting for the value to change.
+//!
+//! Besides shared and exclusive access the lock offers upgradable access: it
+//! coexists with shared holders but excludes writers and other upgradable
+//! holders, so it can later become exclusive without letting a writer in.
+//!
+//! Every lock gets a process-wide id. Named locks are listed in the registry
+//! and keep track of the threads holding them, so the registry can report
+//! who holds what without taking the lock. Unnamed ones skip both, which
+//! keeps bag creation and uncontended acquisitions off any global lock.
+//!
+//! Async acquisitions register a `Waker` in the same state. They follow the
+//! same rules as blocking ones, and every release wakes all pending tasks in
+//! the order they started waiting.
+//!
+//! With the `deadlock-detection` feature every hold also records the thread
+//! that acquired it, and blocking acquisitions are checked against a global
+//! lock-order graph first, see `deadlock`. With the `metrics` feature every
+//! acquisition and release is timed and recorded, see `crate::metrics`.
+
+use std::collections::VecDeque;
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, Thread, ThreadId};
+use std::time::{Duration, Instant};
+
+use crate::errors::TryLockError;
+use crate::registry::{self, BagInfo, Holder};
+#[cfg(feature = "metrics")]
+use crate::metrics::{LockMetrics, LockObserver};
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+
+#[cfg(feature = "deadlock-detection")]
+mod deadlock;
+
+/// How long an acquisition is allowed to block
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Wait {
+    /// Block until the lock is available
+    Forever,
+    /// Fail right away if the lock is contended
+    Never,
+    /// Block until the lock is available or the deadline passes
+    Until(Instant),
+}
+
+impl Wait {
+    /// Waits at most `timeout` from now
+    pub(crate) fn timeout(timeout: Duration) -> Wait {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => Wait::Until(deadline),
+            None => Wait::Forever,
+        }
+    }
+}
+
+/// Which waiters a contended iBag lets in first
+///
+/// The policy only matters when readers and writers compete; an
+/// uncontended lock behaves the same under every policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockPolicy {
+    /// A waiting writer blocks new readers
+    ///
+    /// Readers that already hold the lock finish, then the writer goes.
+    /// A steady stream of writers can starve readers, but not the other way
+    /// around. This is the default.
+    #[default]
+    WriterPreferring,
+    /// Readers get in whenever no writer holds the lock
+    ///
+    /// Gives the best read throughput, but a steady stream of readers can
+    /// starve writers and upgrades indefinitely.
+    ReaderPreferring,
+    /// Contended acquisitions are served strictly in arrival order
+    ///
+    /// Consecutive readers in the queue share the lock; a writer waits for
+    /// the readers ahead of it, and readers behind it wait for the writer.
+    /// Nobody starves, at the cost of some read throughput.
+    Fair,
+}
+
+/// The kind of access a hold or a lock event is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LockKind {
+    /// Shared access, as taken by `load` and `with_read`
+    Read,
+    /// Upgradable access, as taken by `upgradable_load`
+    Upgradable,
+    /// Exclusive access, as taken by `write` and `with`, or by an upgrade
+    Write,
+}
+
+struct State {
+    policy: LockPolicy,
+    readers: usize,
+    writer: bool,
+    upgradable: bool,
+    /// An upgradable holder is waiting for the readers to leave
+    upgrading: bool,
+    waiting_writers: usize,
+    /// Tickets of contended acquisitions in arrival order, `Fair` only
+    queue: VecDeque<u64>,
+    /// Pending async acquisitions, oldest first
+    wakers: Vec<(u64, Waker)>,
+    next_waiter: u64,
+    /// Whether `holders` is kept, only for locks listed in the registry
+    tracked: bool,
+    /// Contended acquisitions, upgrades and change waits still blocked,
+    /// for tests to wait on
+    #[cfg(test)]
+    waiting: usize,
+    /// Every granted hold and the thread that acquired it
+    holders: Vec<(LockKind, Thread)>,
+}
+
+impl State {
+    /// Whether `access` is compatible with the current holders and, where
+    /// the policy says so, with the waiting writers
+    fn compatible(&self, access: LockKind) -> bool {
+        match access {
+            LockKind::Read => {
+                !self.writer
+                    && match self.policy {
+                        LockPolicy::WriterPreferring => self.waiting_writers == 0 && !self.upgrading,
+                        LockPolicy::ReaderPreferring => true,
+                        LockPolicy::Fair => !self.upgrading,
+                    }
+            }
+            LockKind::Upgradable => self.compatible(LockKind::Read) && !self.upgradable,
+            LockKind::Write => !self.writer && self.readers == 0 && !self.upgradable,
+        }
+    }
+
+    /// Whether the holder of `ticket` (or a newcomer, for `None`) may take
+    /// `access` now
+    fn ready(&self, access: LockKind, ticket: Option<u64>) -> bool {
+        if !self.compatible(access) {
+            return false;
+        }
+        match self.policy {
+            LockPolicy::Fair => self.queue.front().copied() == ticket,
+            _ => true,
+        }
+    }
+
+    fn grant(&mut self, access: LockKind) {
+        match access {
+            LockKind::Read => self.readers += 1,
+            LockKind::Upgradable => self.upgradable = true,
+            LockKind::Write => self.writer = true,
+        }
+        if self.tracked {
+            self.holders.push((access, current_thread()));
+        }
+    }
+
+    fn holder(&mut self, access: LockKind, thread: ThreadId) -> Option<&mut (LockKind, Thread)> {
+        self.holders
+            .iter_mut()
+            .find(|(kind, holder)| *kind == access && holder.id() == thread)
+    }
+
+    /// Forgets the hold `thread` took, which may be released elsewhere
+    fn release_holder(&mut self, access: LockKind, thread: ThreadId) {
+        let pos = self
+            .holders
+            .iter()
+            .position(|(kind, holder)| *kind == access && holder.id() == thread);
+        if let Some(pos) = pos {
+            self.holders.swap_remove(pos);
+        }
+    }
+
+    fn convert_holder(&mut self, from: LockKind, to: LockKind, thread: ThreadId) {
+        if let Some(holder) = self.holder(from, thread) {
+            holder.0 = to;
+        }
+    }
+
+    /// Records a contended acquisition and returns its ticket
+    fn enqueue(&mut self, access: LockKind) -> u64 {
+        self.next_waiter += 1;
+        #[cfg(test)]
+        {
+            self.waiting += 1;
+        }
+        if access == LockKind::Write {
+            self.waiting_writers += 1;
+        }
+        if self.policy == LockPolicy::Fair {
+            self.queue.push_back(self.next_waiter);
+        }
+        self.next_waiter
+    }
+
+    /// Forgets a contended acquisition, whether it succeeded or gave up
+    fn dequeue(&mut self, access: LockKind, ticket: u64) {
+        #[cfg(test)]
+        {
+            self.waiting -= 1;
+        }
+        if access == LockKind::Write {
+            self.waiting_writers -= 1;
+        }
+        if let Some(pos) = self.queue.iter().position(|&other| other == ticket) {
+            self.queue.remove(pos);
+        }
+        self.wakers.retain(|(other, _)| *other != ticket);
+    }
+
+    /// Whether a successful acquisition must wake the others, because the
+    /// next waiter in the fair queue may be able to go as well
+    fn pass_on(&self) -> bool {
+        !self.queue.is_empty()
+    }
+}
+
+/// A reader-writer lock that does not own the data it protects
+pub(crate) struct RawLock {
+    state: Mutex<State>,
+    cond: Condvar,
+    poisoned: AtomicBool,
+    /// Number of completed exclusive holds, only bumped under `state`
+    version: AtomicU64,
+    changed: Condvar,
+    id: u64,
+    name: Option<Box<str>>,
+    type_name: &'static str,
+    created: Instant,
+    #[cfg(feature = "deadlock-detection")]
+    order: deadlock::LockId,
+    #[cfg(feature = "metrics")]
+    metrics: LockMetrics,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT: Thread = thread::current();
+}
+
+/// A handle to the current thread, cached to keep acquisitions cheap
+fn current_thread() -> Thread {
+    CURRENT.try_with(Thread::clone).unwrap_or_else(|_| thread::current())
+}
+
+/// The current thread's id, without cloning its handle
+fn current_thread_id() -> ThreadId {
+    CURRENT.try_with(Thread::id).unwrap_or_else(|_| thread::current().id())
+}
+
+impl Drop for RawLock {
+    fn drop(&mut self) {
+        if self.listed() {
+            registry::unregister(self.id);
+        }
+    }
+}
+
+impl RawLock {
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub(crate) fn new(policy: LockPolicy) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        RawLock {
+            state: Mutex::new(State {
+                policy,
+                readers: 0,
+                writer: false,
+                upgradable: false,
+                upgrading: false,
+                waiting_writers: 0,
+                queue: VecDeque::new(),
+                wakers: Vec::new(),
+                next_waiter: 0,
+                tracked: false,
+                #[cfg(test)]
+                waiting: 0,
+                holders: Vec::new(),
+            }),
+            cond: Condvar::new(),
+            poisoned: AtomicBool::new(false),
+            version: AtomicU64::new(0),
+            changed: Condvar::new(),
+            id,
+            name: None,
+            type_name: "",
+            created: Instant::now(),
+            #[cfg(feature = "deadlock-detection")]
+            order: deadlock::LockId::new(id),
+            #[cfg(feature = "metrics")]
+            metrics: LockMetrics::new(None),
+        }
+    }
+
+    /// Sets what the registry reports about the lock
+    ///
+    /// Only a named lock is listed and tracks its holders.
+    pub(crate) fn described(mut self, name: Option<String>, type_name: &'static str) -> Self {
+        self.state.get_mut().unwrap_or_else(PoisonError::into_inner).tracked = name.is_some();
+        self.name = name.map(String::into_boxed_str);
+        self.type_name = type_name;
+        self
+    }
+
+    /// Whether the lock is listed in the registry
+    pub(crate) fn listed(&self) -> bool {
+        self.name.is_some()
+    }
+
+    pub(crate) fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns what the registry reports about the lock, without waiting
+    /// for the lock itself
+    pub(crate) fn info(&self) -> BagInfo {
+        let state = self.state();
+        BagInfo {
+            id: self.id,
+            name: self.name.as_deref().map(str::to_owned),
+            type_name: self.type_name,
+            age: self.created.elapsed(),
+            readers: state.readers,
+            writer: state.writer,
+            upgradable: state.upgradable,
+            waiting_writers: state.waiting_writers,
+            holders: state
+                .holders
+                .iter()
+                .map(|(kind, thread)| Holder {
+                    kind: *kind,
+                    thread: thread.id(),
+                    thread_name: thread.name().map(str::to_owned),
+                })
+                .collect(),
+            version: self.version(),
+            poisoned: self.is_poisoned(),
+        }
+    }
+
+    /// Lists the lock in the registry, if it is named
+    ///
+    /// The lock must not move until it is dropped.
+    pub(crate) fn register(&self) {
+        if self.listed() {
+            registry::register(self.id, self);
+        }
+    }
+
+    /// Returns a new, unlocked lock described and observed like this one
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub(crate) fn detached(&self) -> RawLock {
+        let raw = RawLock::new(self.policy()).described(self.name.as_deref().map(str::to_owned), self.type_name);
+        #[cfg(feature = "metrics")]
+        let raw = raw.observed(self.metrics.observer());
+        raw
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Reports every lock event to `observer` as well
+    #[cfg(feature = "metrics")]
+    pub(crate) fn observed(mut self, observer: Option<Arc<dyn LockObserver>>) -> Self {
+        self.metrics = LockMetrics::new(observer);
+        self
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn metrics(&self) -> &LockMetrics {
+        &self.metrics
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // The state mutex is never held across user code, so a poisoned
+        // state is still consistent.
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Blocks on the condition variable once
+    ///
+    /// Hands the state back as `Err` if the caller may not wait any longer.
+    fn block<'a>(
+        &'a self,
+        state: MutexGuard<'a, State>,
+        wait: Wait,
+    ) -> Result<MutexGuard<'a, State>, MutexGuard<'a, State>> {
+        match wait {
+            Wait::Forever => Ok(self.cond.wait(state).unwrap_or_else(PoisonError::into_inner)),
+            Wait::Never => Err(state),
+            Wait::Until(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(state);
+                }
+                let (state, _) = self
+                    .cond
+                    .wait_timeout(state, deadline - now)
+                    .unwrap_or_else(PoisonError::into_inner);
+                Ok(state)
+            }
+        }
+    }
+
+    pub(crate) fn policy(&self) -> LockPolicy {
+        self.state().policy
+    }
+
+    /// Returns the number of threads and tasks blocked on the lock
+    #[cfg(test)]
+    pub(crate) fn waiting(&self) -> usize {
+        self.state().waiting
+    }
+
+    /// Acquires shared access
+    pub(crate) fn lock_shared(&self, wait: Wait) -> Result<SharedHold<'_>, TryLockError> {
+        self.acquire(LockKind::Read, wait)?;
+        Ok(SharedHold::new(self))
+    }
+
+    /// Acquires upgradable access
+    pub(crate) fn lock_upgradable(&self, wait: Wait) -> Result<UpgradableHold<'_>, TryLockError> {
+        self.acquire(LockKind::Upgradable, wait)?;
+        Ok(UpgradableHold::new(self))
+    }
+
+    /// Acquires exclusive access
+    pub(crate) fn lock_exclusive(&self, wait: Wait) -> Result<ExclusiveHold<'_>, TryLockError> {
+        self.acquire(LockKind::Write, wait)?;
+        Ok(ExclusiveHold::new(self))
+    }
+
+    fn acquire(&self, access: LockKind, wait: Wait) -> Result<(), TryLockError> {
+        #[cfg(feature = "deadlock-detection")]
+        if let Wait::Forever = wait {
+            deadlock::before_blocking(&self.order);
+        }
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        let result = self.wait_for(access, wait);
+        #[cfg(feature = "metrics")]
+        self.record(access, start, &result);
+        result.map(drop)
+    }
+
+    /// Records the outcome of an acquisition that started at `start`
+    #[cfg(feature = "metrics")]
+    fn record(&self, access: LockKind, start: Instant, result: &Result<bool, TryLockError>) {
+        match result {
+            Ok(contended) => self.metrics.acquired(access, start.elapsed(), *contended),
+            Err(_) => self.metrics.failed(access, start.elapsed()),
+        }
+    }
+
+    /// Waits until `access` is granted
+    ///
+    /// Returns whether the acquisition was contended.
+    fn wait_for(&self, access: LockKind, wait: Wait) -> Result<bool, TryLockError> {
+        let mut state = self.state();
+        if state.ready(access, None) {
+            state.grant(access);
+            return Ok(false);
+        }
+        let ticket = state.enqueue(access);
+        loop {
+            state = match self.block(state, wait) {
+                Ok(state) => state,
+                Err(mut state) => {
+                    state.dequeue(access, ticket);
+                    // Others may have been held back by this waiter.
+                    self.notify(state);
+                    return Err(failure(wait));
+                }
+            };
+            if state.ready(access, Some(ticket)) {
+                state.dequeue(access, ticket);
+                state.grant(access);
+                if state.pass_on() {
+                    self.notify(state);
+                }
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Turns upgradable access into exclusive access once the remaining
+    /// shared holders are gone
+    ///
+    /// The upgrade does not queue: it already holds the only upgradable
+    /// slot, so no writer can be ahead of it.
+    fn upgrade(&self, wait: Wait, thread: ThreadId) -> Result<(), TryLockError> {
+        #[cfg(feature = "deadlock-detection")]
+        if let Wait::Forever = wait {
+            deadlock::before_upgrade(&self.order);
+        }
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        let result = self.wait_for_upgrade(wait, thread);
+        #[cfg(feature = "metrics")]
+        self.record(LockKind::Write, start, &result);
+        result.map(drop)
+    }
+
+    fn wait_for_upgrade(&self, wait: Wait, thread: ThreadId) -> Result<bool, TryLockError> {
+        let mut state = self.state();
+        let contended = state.readers > 0;
+        state.upgrading = true;
+        #[cfg(test)]
+        {
+            state.waiting += 1;
+        }
+        while state.readers > 0 {
+            state = match self.block(state, wait) {
+                Ok(state) => state,
+                Err(mut state) => {
+                    #[cfg(test)]
+                    {
+                        state.waiting -= 1;
+                    }
+                    state.upgrading = false;
+                    self.notify(state);
+                    return Err(failure(wait));
+                }
+            };
+        }
+        #[cfg(test)]
+        {
+            state.waiting -= 1;
+        }
+        state.upgrading = false;
+        state.upgradable = false;
+        state.writer = true;
+        state.convert_holder(LockKind::Upgradable, LockKind::Write, thread);
+        Ok(contended)
+    }
+
+    /// Wakes every blocked thread and pending task after a state change
+    fn notify(&self, mut state: MutexGuard<'_, State>) {
+        let wakers = std::mem::take(&mut state.wakers);
+        drop(state);
+        self.cond.notify_all();
+        for (_, waker) in wakers {
+            waker.wake();
+        }
+    }
+
+    fn unlock_shared(&self, thread: ThreadId) {
+        let mut state = self.state();
+        state.release_holder(LockKind::Read, thread);
+        state.readers -= 1;
+        if state.readers == 0 {
+            self.notify(state);
+        }
+    }
+
+    fn unlock_upgradable(&self, downgrade: bool, thread: ThreadId) {
+        let mut state = self.state();
+        state.upgradable = false;
+        if downgrade {
+            state.readers += 1;
+            state.convert_holder(LockKind::Upgradable, LockKind::Read, thread);
+        } else {
+            state.release_holder(LockKind::Upgradable, thread);
+        }
+        self.notify(state);
+    }
+
+    fn unlock_exclusive(&self, changed: bool, downgrade: bool, thread: ThreadId) {
+        let mut state = self.state();
+        state.writer = false;
+        if downgrade {
+            state.readers += 1;
+            state.convert_holder(LockKind::Write, LockKind::Read, thread);
+        } else {
+            state.release_holder(LockKind::Write, thread);
+        }
+        if changed {
+            self.version.fetch_add(1, Ordering::Release);
+        }
+        self.notify(state);
+        if changed {
+            self.changed.notify_all();
+        }
+    }
+
+    pub(crate) fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// Publishes a change made without the lock, by an owner that has the
+    /// only handle
+    pub(crate) fn bump_version(&self) {
+        let _state = self.state();
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    /// Blocks until the version differs from `seen`
+    ///
+    /// Returns the new version, or `None` if the caller may not wait any
+    /// longer.
+    pub(crate) fn wait_for_change(&self, seen: u64, wait: Wait) -> Option<u64> {
+        let mut state = self.state();
+        #[cfg(test)]
+        {
+            state.waiting += 1;
+        }
+        let changed = loop {
+            let version = self.version();
+            if version != seen {
+                break Some(version);
+            }
+            state = match wait {
+                Wait::Forever => self.changed.wait(state).unwrap_or_else(PoisonError::into_inner),
+                Wait::Never => break None,
+                Wait::Until(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break None;
+                    }
+                    self.changed
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        };
+        #[cfg(test)]
+        {
+            state.waiting -= 1;
+        }
+        drop(state);
+        changed
+    }
+
+    pub(crate) fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+}
+
+/// A pending async acquisition of a `RawLock`
+///
+/// Dropping it before it completes withdraws the registration.
+pub(crate) struct AsyncWait<'a> {
+    raw: &'a RawLock,
+    /// The ticket and access of the acquisition once it had to wait
+    ticket: Option<(u64, LockKind)>,
+    #[cfg(feature = "metrics")]
+    start: Instant,
+}
+
+impl<'a> AsyncWait<'a> {
+    pub(crate) fn new(raw: &'a RawLock) -> Self {
+        AsyncWait {
+            raw,
+            ticket: None,
+            #[cfg(feature = "metrics")]
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn poll_shared(&mut self, cx: &mut Context<'_>) -> Poll<SharedHold<'a>> {
+        self.poll(LockKind::Read, cx).map(|()| SharedHold::new(self.raw))
+    }
+
+    pub(crate) fn poll_exclusive(&mut self, cx: &mut Context<'_>) -> Poll<ExclusiveHold<'a>> {
+        self.poll(LockKind::Write, cx).map(|()| ExclusiveHold::new(self.raw))
+    }
+
+    fn poll(&mut self, access: LockKind, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.raw.state();
+        if state.ready(access, self.ticket.map(|(ticket, _)| ticket)) {
+            #[cfg(feature = "metrics")]
+            let contended = self.ticket.is_some();
+            if let Some((ticket, access)) = self.ticket.take() {
+                state.dequeue(access, ticket);
+            }
+            state.grant(access);
+            if state.pass_on() {
+                self.raw.notify(state);
+            } else {
+                drop(state);
+            }
+            #[cfg(feature = "metrics")]
+            self.raw.metrics.acquired(access, self.start.elapsed(), contended);
+            return Poll::Ready(());
+        }
+        // Queued like a blocking waiter, so a pending writer holds back new
+        // readers and fair ordering covers tasks too.
+        let (ticket, _) = *self.ticket.get_or_insert_with(|| (state.enqueue(access), access));
+        match state.wakers.iter_mut().find(|(other, _)| *other == ticket) {
+            Some((_, registered)) => registered.clone_from(cx.waker()),
+            None => state.wakers.push((ticket, cx.waker().clone())),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for AsyncWait<'_> {
+    fn drop(&mut self) {
+        if let Some((ticket, access)) = self.ticket.take() {
+            let mut state = self.raw.state();
+            state.dequeue(access, ticket);
+            // Others may have been held back by this waiter.
+            self.raw.notify(state);
+            #[cfg(feature = "metrics")]
+            self.raw.metrics.failed(access, self.start.elapsed());
+        }
+    }
+}
+
+fn failure(wait: Wait) -> TryLockError {
+    match wait {
+        Wait::Until(_) => TryLockError::TimedOut,
+        _ => TryLockError::WouldBlock,
+    }
+}
+
+/// Bookkeeping carried by every hold
+///
+/// Records the acquiring thread, which may differ from the releasing one,
+/// and the time of acquisition for `metrics`.
+#[derive(Clone, Copy)]
+struct HoldInfo {
+    thread: ThreadId,
+    #[cfg(feature = "metrics")]
+    since: Instant,
+}
+
+#[cfg_attr(not(feature = "deadlock-detection"), allow(unused_variables))]
+impl HoldInfo {
+    fn acquired(raw: &RawLock, access: LockKind) -> HoldInfo {
+        let thread = current_thread_id();
+        #[cfg(feature = "deadlock-detection")]
+        deadlock::acquired(&raw.order, thread, access);
+        HoldInfo {
+            thread,
+            #[cfg(feature = "metrics")]
+            since: Instant::now(),
+        }
+    }
+
+    /// Moves the hold from `from` to `to` access, as if it was released and
+    /// acquired again
+    #[cfg_attr(not(feature = "metrics"), allow(unused_mut))]
+    fn converted(mut self, raw: &RawLock, from: LockKind, to: LockKind) -> HoldInfo {
+        #[cfg(feature = "deadlock-detection")]
+        deadlock::converted(&raw.order, self.thread, from, to);
+        #[cfg(feature = "metrics")]
+        {
+            raw.metrics.released(from, self.since.elapsed());
+            self.since = Instant::now();
+        }
+        self
+    }
+
+    fn released(self, raw: &RawLock, access: LockKind) {
+        #[cfg(feature = "deadlock-detection")]
+        deadlock::released(&raw.order, self.thread, access);
+        #[cfg(feature = "metrics")]
+        raw.metrics.released(access, self.since.elapsed());
+    }
+}
+
+/// Shared access to a `RawLock`, released on drop
+pub(crate) struct SharedHold<'a> {
+    raw: &'a RawLock,
+    info: HoldInfo,
+}
+
+impl<'a> SharedHold<'a> {
+    fn new(raw: &'a RawLock) -> Self {
+        SharedHold {
+            raw,
+            info: HoldInfo::acquired(raw, LockKind::Read),
+        }
+    }
+}
+
+impl Drop for SharedHold<'_> {
+    fn drop(&mut self) {
+        self.info.released(self.raw, LockKind::Read);
+        self.raw.unlock_shared(self.info.thread);
+    }
+}
+
+/// Exclusive access to a `RawLock`, released on drop
+///
+/// Poisons the lock if the holder panics, like `std::sync::RwLock`.
+pub(crate) struct ExclusiveHold<'a> {
+    raw: &'a RawLock,
+    info: HoldInfo,
+    panicking: bool,
+    changed: bool,
+}
+
+impl<'a> ExclusiveHold<'a> {
+    fn new(raw: &'a RawLock) -> Self {
+        Self::with_info(raw, HoldInfo::acquired(raw, LockKind::Write))
+    }
+
+    fn with_info(raw: &'a RawLock, info: HoldInfo) -> Self {
+        ExclusiveHold {
+            raw,
+            info,
+            panicking: thread::panicking(),
+            changed: true,
+        }
+    }
+
+    /// Releases without publishing a new version
+    ///
+    /// Only for holders that are known not to have touched the value.
+    pub(crate) fn unchanged(&mut self) {
+        self.changed = false;
+    }
+
+    /// Atomically turns exclusive access into shared access
+    pub(crate) fn downgrade(self) -> SharedHold<'a> {
+        let this = ManuallyDrop::new(self);
+        this.release(true);
+        SharedHold {
+            raw: this.raw,
+            info: this.info.converted(this.raw, LockKind::Write, LockKind::Read),
+        }
+    }
+
+    fn release(&self, downgrade: bool) {
+        if !self.panicking && thread::panicking() {
+            self.raw.poisoned.store(true, Ordering::Relaxed);
+        }
+        self.raw.unlock_exclusive(self.changed, downgrade, self.info.thread);
+    }
+}
+
+impl Drop for ExclusiveHold<'_> {
+    fn drop(&mut self) {
+        self.info.released(self.raw, LockKind::Write);
+        self.release(false);
+    }
+}
+
+/// Upgradable access to a `RawLock`, released on drop
+pub(crate) struct UpgradableHold<'a> {
+    raw: &'a RawLock,
+    info: HoldInfo,
+}
+
+impl<'a> UpgradableHold<'a> {
+    fn new(raw: &'a RawLock) -> Self {
+        UpgradableHold {
+            raw,
+            info: HoldInfo::acquired(raw, LockKind::Upgradable),
+        }
+    }
+
+    /// Turns upgradable access into exclusive access
+    ///
+    /// No writer can get in between. Hands the hold back if the caller may
+    /// not wait for the remaining shared holders any longer.
+    pub(crate) fn upgrade(self, wait: Wait) -> Result<ExclusiveHold<'a>, Self> {
+        if self.raw.upgrade(wait, self.info.thread).is_err() {
+            return Err(self);
+        }
+        let this = ManuallyDrop::new(self);
+        let info = this.info.converted(this.raw, LockKind::Upgradable, LockKind::Write);
+        Ok(ExclusiveHold::with_info(this.raw, info))
+    }
+
+    /// Atomically turns upgradable access into shared access
+    pub(crate) fn downgrade(self) -> SharedHold<'a> {
+        let this = ManuallyDrop::new(self);
+        this.raw.unlock_upgradable(true, this.info.thread);
+        SharedHold {
+            raw: this.raw,
+            info: this.info.converted(this.raw, LockKind::Upgradable, LockKind::Read),
+        }
+    }
+}
+
+impl Drop for UpgradableHold<'_> {
+    fn drop(&mut self) {
+        self.info.released(self.raw, LockKind::Upgradable);
+        self.raw.unlock_upgradable(false, self.info.thread);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iBag;
+    use std::sync::Arc;
+    use std::thread::JoinHandle;
+
+    /// Waits until `n` acquisitions of `bag` are queued
+    fn settle(bag: &iBag<u32>, n: usize) {
+        while bag.raw().waiting() < n {
+            thread::yield_now();
+        }
+    }
+
+    /// Spawns a thread that records `tag` in `log` once it gets the lock
+    fn spawn_logged(bag: &iBag<u32>, log: &Arc<Mutex<Vec<&'static str>>>, tag: &'static str) -> JoinHandle<()> {
+        let bag = bag.clone();
+        let log = log.clone();
+        thread::spawn(move || {
+            if tag.starts_with('w') {
+                bag.with(|_| log.lock().unwrap().push(tag));
+            } else {
+                bag.with_read(|_| log.lock().unwrap().push(tag));
+            }
+        })
+    }
+
+    #[test]
+    fn test_writer_preferring_blocks_new_readers() {
+        let bag = iBag::with_policy(0, LockPolicy::WriterPreferring);
+        let read = bag.load();
+        let b = bag.clone();
+        let writer = thread::spawn(move || b.with(|v| *v = 1));
+        settle(&bag, 1);
+        assert_eq!(bag.try_load_now().err(), Some(TryLockError::WouldBlock));
+        drop(read);
+        writer.join().unwrap();
+        assert_eq!(*bag.load(), 1);
+    }
+
+    #[test]
+    fn test_reader_preferring_lets_readers_pass() {
+        let bag = iBag::with_policy(0, LockPolicy::ReaderPreferring);
+        let read = bag.load();
+        let b = bag.clone();
+        let writer = thread::spawn(move || b.with(|v| *v = 1));
+        settle(&bag, 1);
+        let second = bag.try_load_now().unwrap();
+        let upgradable = thread::scope(|s| s.spawn(|| *bag.upgradable_load()).join().unwrap());
+        assert_eq!(upgradable, 0);
+        drop(read);
+        // The writer is still queued behind the second reader.
+        assert_eq!(bag.raw().waiting(), 1);
+        assert_eq!(*second, 0);
+        drop(second);
+        writer.join().unwrap();
+        assert_eq!(*bag.load(), 1);
+    }
+
+    #[test]
+    fn test_fair_serves_in_arrival_order() {
+        let bag = iBag::with_policy(0, LockPolicy::Fair);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let read = bag.load();
+        let w1 = spawn_logged(&bag, &log, "w1");
+        settle(&bag, 1);
+        let r1 = spawn_logged(&bag, &log, "r1");
+        settle(&bag, 2);
+        assert_eq!(bag.try_load_now().err(), Some(TryLockError::WouldBlock));
+        drop(read);
+        w1.join().unwrap();
+        r1.join().unwrap();
+        assert_eq!(*log.lock().unwrap(), ["w1", "r1"]);
+    }
+
+    #[test]
+    fn test_fair_batches_queued_readers() {
+        let bag = iBag::with_policy(0, LockPolicy::Fair);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let write = bag.write();
+        let r1 = spawn_logged(&bag, &log, "r1");
+        let r2 = spawn_logged(&bag, &log, "r2");
+        settle(&bag, 2);
+        let w1 = spawn_logged(&bag, &log, "w1");
+        settle(&bag, 3);
+        drop(write);
+        for handle in [r1, r2, w1] {
+            handle.join().unwrap();
+        }
+        let log = log.lock().unwrap();
+        assert_eq!(log[2], "w1");
+    }
+
+    #[test]
+    fn test_fair_upgrade_goes_before_queued_writer() {
+        let bag = iBag::with_policy(0, LockPolicy::Fair);
+        let upgradable = bag.upgradable_load();
+        let b = bag.clone();
+        let writer = thread::spawn(move || b.with(|v| *v *= 10));
+        settle(&bag, 1);
+        *crate::UpgradableReadGuard::upgrade(upgradable) = 1;
+        writer.join().unwrap();
+        assert_eq!(*bag.load(), 10);
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Lock-order bookkeeping behind the `deadlock-detection` feature.
+//!
+//! Every lock gets an id and remembers where it was created, and every hold
+//! remembers the thread that acquired it. A blocking acquisition adds edges
+//! from the locks its thread already holds to the new one. An acquisition
+//! that asks again for a lock its thread holds, or whose edges would close
+//! a cycle, panics instead of risking a hang.
+//!
+//! Shared acquisitions count too: a waiting writer can turn two readers
+//! taking the same locks in opposite orders into a deadlock.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::panic::Location;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::thread::{self, ThreadId};
+
+use super::LockKind;
+
+#[derive(Default)]
+struct Graph {
+    /// Creation site of every live lock
+    sites: HashMap<u64, &'static Location<'static>>,
+    /// Locks held by each thread, in acquisition order
+    held: HashMap<ThreadId, Vec<(u64, LockKind)>>,
+    /// `a -> b` if `b` was acquired while `a` was held
+    after: HashMap<u64, HashSet<u64>>,
+}
+
+fn graph() -> MutexGuard<'static, Graph> {
+    static GRAPH: OnceLock<Mutex<Graph>> = OnceLock::new();
+    // Nothing panics while the graph is locked, but a panicking test thread
+    // must not take detection down for the others.
+    GRAPH
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Graph {
+    fn describe(&self, id: u64) -> String {
+        match self.sites.get(&id) {
+            Some(site) => format!("bag #{} (created at {})", id, site),
+            None => format!("bag #{}", id),
+        }
+    }
+
+    /// Finds a chain of edges leading from `from` to `to`
+    fn path(&self, from: u64, to: u64) -> Option<Vec<u64>> {
+        let mut came_from = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+        while let Some(id) = queue.pop_front() {
+            if id == to {
+                let mut path = vec![to];
+                while let Some(&prev) = came_from.get(path.last().unwrap()) {
+                    path.push(prev);
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for &next in self.after.get(&id).into_iter().flatten() {
+                if next != from && !came_from.contains_key(&next) {
+                    came_from.insert(next, id);
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The identity of a lock in the graph, removed from it on drop
+pub(crate) struct LockId(u64);
+
+impl LockId {
+    /// Enters the lock with the given id, created by the caller
+    #[track_caller]
+    pub(crate) fn new(id: u64) -> Self {
+        graph().sites.insert(id, Location::caller());
+        LockId(id)
+    }
+}
+
+impl Drop for LockId {
+    fn drop(&mut self) {
+        let mut graph = graph();
+        graph.sites.remove(&self.0);
+        graph.after.remove(&self.0);
+        for after in graph.after.values_mut() {
+            after.remove(&self.0);
+        }
+    }
+}
+
+/// Checks a blocking acquisition of `lock` by the current thread and records
+/// its place in the lock order
+///
+/// # Panics
+/// If the thread already holds `lock`, or if some thread acquired the locks
+/// this one holds after `lock`.
+pub(crate) fn before_blocking(lock: &LockId) {
+    let me = thread::current().id();
+    let mut graph = graph();
+    let mut held: Vec<u64> = graph
+        .held
+        .get(&me)
+        .map(|held| held.iter().map(|&(id, _)| id).collect())
+        .unwrap_or_default();
+    held.dedup();
+
+    if held.contains(&lock.0) {
+        let message = format!(
+            "deadlock: re-entrant acquisition of {}, which this thread already holds",
+            graph.describe(lock.0)
+        );
+        drop(graph);
+        panic!("{}", message);
+    }
+
+    for id in held {
+        if let Some(path) = graph.path(lock.0, id) {
+            let cycle: Vec<String> = path
+                .iter()
+                .chain([&lock.0])
+                .map(|&id| graph.describe(id))
+                .collect();
+            let message = format!("deadlock: lock order cycle {}", cycle.join(" -> "));
+            drop(graph);
+            panic!("{}", message);
+        }
+        graph.after.entry(id).or_default().insert(lock.0);
+    }
+}
+
+/// Checks a blocking upgrade of `lock` by the current thread
+///
+/// # Panics
+/// If the thread also holds shared access to `lock`, which the upgrade
+/// would wait for forever.
+pub(crate) fn before_upgrade(lock: &LockId) {
+    let me = thread::current().id();
+    let graph = graph();
+    let reads = graph
+        .held
+        .get(&me)
+        .is_some_and(|held| held.contains(&(lock.0, LockKind::Read)));
+    if reads {
+        let message = format!(
+            "deadlock: upgrade of {} waits for a read lock held by the same thread",
+            graph.describe(lock.0)
+        );
+        drop(graph);
+        panic!("{}", message);
+    }
+}
+
+/// Records that `owner`, the current thread, acquired `lock`
+pub(crate) fn acquired(lock: &LockId, owner: ThreadId, access: LockKind) {
+    graph().held.entry(owner).or_default().push((lock.0, access));
+}
+
+/// Records that a hold on `lock` changed from `from` to `to` access
+pub(crate) fn converted(lock: &LockId, owner: ThreadId, from: LockKind, to: LockKind) {
+    let mut graph = graph();
+    let entry = graph
+        .held
+        .get_mut(&owner)
+        .and_then(|held| held.iter_mut().rev().find(|entry| **entry == (lock.0, from)));
+    if let Some(entry) = entry {
+        entry.1 = to;
+    }
+}
+
+/// Records that the hold `owner` took on `lock` was released, possibly on
+/// another thread
+pub(crate) fn released(lock: &LockId, owner: ThreadId, access: LockKind) {
+    let mut graph = graph();
+    if let Some(held) = graph.held.get_mut(&owner) {
+        if let Some(pos) = held.iter().rposition(|&entry| entry == (lock.0, access)) {
+            held.remove(pos);
+        }
+        if held.is_empty() {
+            graph.held.remove(&owner);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Lock contention metrics, behind the `metrics` feature.
+//!
+//! Every bag counts its acquisitions and keeps log2 histograms of how long
+//! callers waited for the lock and how long they held it. `iBag::stats()`
+//! takes a snapshot; a `LockObserver` set through the builder sees every
+//! event as it happens.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use crate::lock::LockKind;
+
+/// Number of histogram buckets; bucket `i > 0` counts durations of
+/// `2^(i-1)` up to `2^i - 1` nanoseconds, bucket 0 counts zero
+const BUCKETS: usize = 65;
+
+/// Receives the lock events of a bag as they happen
+///
+/// Set with `BagBuilder::observer`. Methods are called on the thread that
+/// acquires or releases the lock, outside of the lock's internal state, so
+/// they should be quick but may take locks of their own. Every method does
+/// nothing by default.
+///
+/// # Examples
+/// ```
+/// use ibag::iBag;
+/// use ibag::metrics::{LockKind, LockObserver};
+/// use std::sync::atomic::{AtomicU64, Ordering};
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// #[derive(Default)]
+/// struct SlowWrites(AtomicU64);
+///
+/// impl LockObserver for SlowWrites {
+///     fn acquired(&self, kind: LockKind, waited: Duration, _contended: bool) {
+///         if kind == LockKind::Write && waited > Duration::from_millis(10) {
+///             self.0.fetch_add(1, Ordering::Relaxed);
+///         }
+///     }
+/// }
+///
+/// let observer = Arc::new(SlowWrites::default());
+/// let bag = iBag::builder().observer(observer.clone()).build(0);
+/// bag.with(|v| *v += 1);
+/// assert_eq!(observer.0.load(Ordering::Relaxed), 0);
+/// ```
+pub trait LockObserver: Send + Sync {
+    /// Called after access was acquired
+    ///
+    /// `contended` is `true` if the caller could not get the lock right
+    /// away.
+    fn acquired(&self, _kind: LockKind, _waited: Duration, _contended: bool) {}
+
+    /// Called after access was released
+    fn released(&self, _kind: LockKind, _held: Duration) {}
+
+    /// Called when an acquisition gave up, because the lock was busy, a
+    /// timeout elapsed or an async acquisition was dropped
+    fn failed(&self, _kind: LockKind, _waited: Duration) {}
+}
+
+/// A snapshot of a log2 histogram of durations
+#[derive(Clone, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    sum: u64,
+    max: u64,
+}
+
+impl Histogram {
+    /// Returns the number of recorded durations
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Returns the sum of all recorded durations
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(self.sum)
+    }
+
+    /// Returns the mean recorded duration, or zero if nothing was recorded
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos(self.sum / count),
+        }
+    }
+
+    /// Returns the longest recorded duration
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// Returns an upper bound for the given quantile, `0.0..=1.0`
+    ///
+    /// The bound is the upper end of the bucket the quantile falls into,
+    /// so it is within a factor of two of the exact value.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return upper_bound(i).min(self.max());
+            }
+        }
+        self.max()
+    }
+
+    /// Iterates over the non-empty buckets as `(upper bound, count)`
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|&(_, &n)| n > 0)
+            .map(|(i, &n)| (upper_bound(i), n))
+    }
+}
+
+fn upper_bound(bucket: usize) -> Duration {
+    match bucket {
+        0 => Duration::ZERO,
+        64 => Duration::from_nanos(u64::MAX),
+        i => Duration::from_nanos((1 << i) - 1),
+    }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Histogram")
+            .field("count", &self.count())
+            .field("mean", &self.mean())
+            .field("p99", &self.quantile(0.99))
+            .field("max", &self.max())
+            .finish()
+    }
+}
+
+/// Counters and histograms for one kind of access
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessStats {
+    /// Successful acquisitions
+    pub acquisitions: u64,
+    /// Acquisitions that could not get the lock right away
+    pub contended: u64,
+    /// Acquisitions that gave up
+    pub failed: u64,
+    /// Time spent waiting by successful acquisitions
+    pub wait: Histogram,
+    /// Time the lock was held
+    pub hold: Histogram,
+}
+
+/// A snapshot of the lock metrics of a bag
+///
+/// Returned by `iBag::stats()`. The counters are read one by one while
+/// other threads may be using the bag, so they are not a consistent cut.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockStats {
+    /// Shared access
+    pub read: AccessStats,
+    /// Upgradable access
+    pub upgradable: AccessStats,
+    /// Exclusive access, including upgrades
+    pub write: AccessStats,
+}
+
+impl LockStats {
+    /// Returns the stats for one kind of access
+    pub fn kind(&self, kind: LockKind) -> &AccessStats {
+        match kind {
+            LockKind::Read => &self.read,
+            LockKind::Upgradable => &self.upgradable,
+            LockKind::Write => &self.write,
+        }
+    }
+}
+
+struct AtomicHistogram {
+    buckets: [AtomicU64; BUCKETS],
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl AtomicHistogram {
+    fn new() -> Self {
+        AtomicHistogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - nanos.leading_zeros()) as usize;
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            sum: self.sum.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct AccessMetrics {
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    failed: AtomicU64,
+    wait: AtomicHistogram,
+    hold: AtomicHistogram,
+}
+
+impl AccessMetrics {
+    fn new() -> Self {
+        AccessMetrics {
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            wait: AtomicHistogram::new(),
+            hold: AtomicHistogram::new(),
+        }
+    }
+
+    fn snapshot(&self) -> AccessStats {
+        AccessStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            wait: self.wait.snapshot(),
+            hold: self.hold.snapshot(),
+        }
+    }
+}
+
+/// The metrics recorded by one lock
+pub(crate) struct LockMetrics {
+    kinds: [AccessMetrics; 3],
+    observer: Option<Arc<dyn LockObserver>>,
+}
+
+impl LockMetrics {
+    pub(crate) fn new(observer: Option<Arc<dyn LockObserver>>) -> Self {
+        LockMetrics {
+            kinds: std::array::from_fn(|_| AccessMetrics::new()),
+            observer,
+        }
+    }
+
+    pub(crate) fn observer(&self) -> Option<Arc<dyn LockObserver>> {
+        self.observer.clone()
+    }
+
+    fn kind(&self, kind: LockKind) -> &AccessMetrics {
+        &self.kinds[kind as usize]
+    }
+
+    pub(crate) fn acquired(&self, kind: LockKind, waited: Duration, contended: bool) {
+        let metrics = self.kind(kind);
+        metrics.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if contended {
+            metrics.contended.fetch_add(1, Ordering::Relaxed);
+        }
+        metrics.wait.record(waited);
+        if let Some(observer) = &self.observer {
+            observer.acquired(kind, waited, contended);
+        }
+    }
+
+    pub(crate) fn released(&self, kind: LockKind, held: Duration) {
+        self.kind(kind).hold.record(held);
+        if let Some(observer) = &self.observer {
+            observer.released(kind, held);
+        }
+    }
+
+    pub(crate) fn failed(&self, kind: LockKind, waited: Duration) {
+        self.kind(kind).failed.fetch_add(1, Ordering::Relaxed);
+        if let Some(observer) = &self.observer {
+            observer.failed(kind, waited);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> LockStats {
+        LockStats {
+            read: self.kind(LockKind::Read).snapshot(),
+            upgradable: self.kind(LockKind::Upgradable).snapshot(),
+            write: self.kind(LockKind::Write).snapshot(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = AtomicHistogram::new();
+        for nanos in [0, 1, 3, 4, 1000, 1000] {
+            histogram.record(Duration::from_nanos(nanos));
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count(), 6);
+        assert_eq!(snapshot.max(), Duration::from_nanos(1000));
+        assert_eq!(snapshot.total(), Duration::from_nanos(2008));
+        let buckets: Vec<_> = snapshot.buckets().map(|(bound, n)| (bound.as_nanos(), n)).collect();
+        assert_eq!(buckets, [(0, 1), (1, 1), (3, 1), (7, 1), (1023, 2)]);
+        assert_eq!(snapshot.quantile(0.5), Duration::from_nanos(3));
+        assert_eq!(snapshot.quantile(1.0), Duration::from_nanos(1000));
+    }
+
+    #[test]
+    fn test_empty_histogram() {
+        let snapshot = AtomicHistogram::new().snapshot();
+        assert_eq!(snapshot.count(), 0);
+        assert_eq!(snapshot.mean(), Duration::ZERO);
+        assert_eq!(snapshot.quantile(0.99), Duration::ZERO);
+    }
+}
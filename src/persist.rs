@@ -0,0 +1,681 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! An iBag whose value survives restarts.
+//!
+//! `PersistentBag` keeps two files next to each other: a snapshot at the
+//! given path and a write-ahead log at the same path with `.wal` appended.
+//! Every write appends the whole encoded value to the log and syncs it
+//! before the write lock is released, so a write that returned `Ok` is
+//! durable. Snapshots are written to a temporary file and renamed over the
+//! old one, after every write or at most once per debounce interval, and
+//! the log is emptied once a snapshot covers it.
+//!
+//! Every record carries a sequence number and a CRC32. On startup the
+//! newest of the snapshot and the intact log records wins; a record torn by
+//! a crash ends the log.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::bag::{iBag, ReadGuard};
+use crate::handle::iBagReader;
+
+/// Turns values into bytes and back for a `PersistentBag`
+///
+/// # Examples
+/// ```
+/// use ibag::persist::Codec;
+/// use std::io;
+///
+/// struct Counter;
+///
+/// impl Codec<u64> for Counter {
+///     fn encode(&self, value: &u64) -> io::Result<Vec<u8>> {
+///         Ok(value.to_le_bytes().to_vec())
+///     }
+///
+///     fn decode(&self, bytes: &[u8]) -> io::Result<u64> {
+///         let bytes = bytes.try_into().map_err(|_| io::ErrorKind::InvalidData)?;
+///         Ok(u64::from_le_bytes(bytes))
+///     }
+/// }
+/// ```
+pub trait Codec<T>: Send + Sync {
+    /// Encodes the whole value
+    fn encode(&self, value: &T) -> io::Result<Vec<u8>>;
+
+    /// Decodes a value written by `encode`
+    fn decode(&self, bytes: &[u8]) -> io::Result<T>;
+}
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"IBAGSNP1";
+
+/// Size of a log record header: length, CRC32 and sequence number
+const RECORD_HEADER: usize = 4 + 4 + 8;
+
+/// CRC-32 (IEEE) of `bytes`
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Returns `path` with `suffix` appended to its file name
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn read_file(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match File::open(path) {
+        Ok(mut file) => {
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+            Ok(Some(bytes))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Parses a snapshot file into its sequence number and payload
+fn parse_snapshot(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let rest = bytes.strip_prefix(SNAPSHOT_MAGIC)?;
+    let (seq, rest) = rest.split_first_chunk::<8>()?;
+    let (crc, payload) = rest.split_first_chunk::<4>()?;
+    let seq = u64::from_le_bytes(*seq);
+    let mut covered = seq.to_le_bytes().to_vec();
+    covered.extend_from_slice(payload);
+    (crc32(&covered) == u32::from_le_bytes(*crc)).then_some((seq, payload))
+}
+
+/// Returns the last intact record of a log, stopping at the first torn or
+/// corrupt one
+fn last_record(mut log: &[u8]) -> Option<(u64, &[u8])> {
+    let mut last = None;
+    while log.len() >= RECORD_HEADER {
+        let len = u32::from_le_bytes(log[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(log[4..8].try_into().unwrap());
+        let Some(body) = log[8..].get(..8 + len) else {
+            break;
+        };
+        if crc32(body) != crc {
+            break;
+        }
+        let seq = u64::from_le_bytes(body[..8].try_into().unwrap());
+        last = Some((seq, &body[8..]));
+        log = &log[8 + body.len()..];
+    }
+    last
+}
+
+fn record(seq: u64, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(8 + payload.len());
+    body.extend_from_slice(&seq.to_le_bytes());
+    body.extend_from_slice(payload);
+    let mut record = Vec::with_capacity(8 + body.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32(&body).to_le_bytes());
+    record.extend_from_slice(&body);
+    record
+}
+
+/// The write-ahead log, a trait so that tests can make it fail
+trait Log: Write + Send {
+    /// Returns the length of the log in bytes
+    fn size(&mut self) -> io::Result<u64>;
+
+    /// Cuts the log to `len` bytes and continues writing from there
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl Log for File {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)?;
+        self.seek(SeekFrom::Start(len))?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+struct State {
+    log: Box<dyn Log>,
+    /// Sequence number of the current value
+    seq: u64,
+    /// The current value, encoded
+    latest: Arc<[u8]>,
+    /// Sequence number of the value in the snapshot file
+    snapshot_seq: u64,
+    /// Set by writes for the debounce thread
+    dirty: bool,
+    closed: bool,
+    /// Set when a failed append could not be cut off again. Recovery stops
+    /// at a torn record, so nothing may be logged after it until a snapshot
+    /// empties the log.
+    broken: bool,
+}
+
+struct Store<T> {
+    codec: Box<dyn Codec<T>>,
+    path: PathBuf,
+    state: Mutex<State>,
+    wake: Condvar,
+    /// Serializes snapshot writers
+    writing: Mutex<()>,
+}
+
+impl<T> Store<T> {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Appends `value` to the log and syncs it
+    fn append(&self, value: &T) -> io::Result<()> {
+        let payload = self.codec.encode(value)?;
+        if u32::try_from(payload.len()).is_err() {
+            return Err(invalid_data("encoded value larger than 4 GiB"));
+        }
+        let mut state = self.state();
+        if state.broken {
+            return Err(io::Error::other("log unusable after a failed write could not be undone"));
+        }
+        let seq = state.seq + 1;
+        let len = state.log.size()?;
+        let logged = state.log.write_all(&record(seq, &payload));
+        if let Err(err) = logged.and_then(|()| state.log.sync()) {
+            // A partial record would hide every later one from recovery.
+            if state.log.truncate(len).and_then(|()| state.log.sync()).is_err() {
+                state.broken = true;
+            }
+            return Err(err);
+        }
+        state.seq = seq;
+        state.latest = payload.into();
+        state.dirty = true;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Decodes the last durable value
+    fn latest(&self) -> io::Result<T> {
+        let latest = self.state().latest.clone();
+        self.codec.decode(&latest)
+    }
+
+    /// Writes the current value as the snapshot and empties the log
+    fn snapshot(&self) -> io::Result<()> {
+        let _writing = self.writing.lock().unwrap_or_else(PoisonError::into_inner);
+        let (seq, latest) = {
+            let state = self.state();
+            if state.snapshot_seq == state.seq && !state.broken {
+                return Ok(());
+            }
+            (state.seq, state.latest.clone())
+        };
+        write_snapshot(&self.path, seq, &latest)?;
+        let mut state = self.state();
+        state.snapshot_seq = seq;
+        // Records up to `seq` are covered now. Newer ones stay until the
+        // next snapshot; recovery skips the covered ones either way.
+        if state.seq == seq {
+            state.log.truncate(0)?;
+            state.log.sync()?;
+            state.broken = false;
+        }
+        Ok(())
+    }
+
+    /// Snapshots at most once per `interval` until closed
+    fn run_debounced(&self, interval: Duration) {
+        loop {
+            let mut state = self.state();
+            while !state.dirty && !state.closed {
+                state = self.wake.wait(state).unwrap_or_else(PoisonError::into_inner);
+            }
+            let deadline = Instant::now() + interval;
+            while !state.closed {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                state = self
+                    .wake
+                    .wait_timeout(state, deadline - now)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+            state.dirty = false;
+            let closed = state.closed;
+            drop(state);
+            // A failed snapshot is retried after the next write; the log
+            // keeps the writes durable meanwhile.
+            let _ = self.snapshot();
+            if closed {
+                return;
+            }
+        }
+    }
+}
+
+fn write_snapshot(path: &Path, seq: u64, payload: &[u8]) -> io::Result<()> {
+    let tmp = sibling(path, ".tmp");
+    let mut covered = seq.to_le_bytes().to_vec();
+    covered.extend_from_slice(payload);
+    let mut file = File::create(&tmp)?;
+    file.write_all(SNAPSHOT_MAGIC)?;
+    file.write_all(&seq.to_le_bytes())?;
+    file.write_all(&crc32(&covered).to_le_bytes())?;
+    file.write_all(payload)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)?;
+    sync_dir(path);
+    Ok(())
+}
+
+/// Makes a rename in the directory of `path` durable, where the platform
+/// allows syncing a directory
+fn sync_dir(path: &Path) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// An iBag that is saved to a file and restored from it
+///
+/// Reads work as on any iBag. Writes go through `with`, which logs the new
+/// value before anyone else can see it and reports I/O errors.
+///
+/// # Examples
+/// ```
+/// use ibag::persist::{Codec, PersistentBag};
+/// use std::io;
+///
+/// struct Text;
+///
+/// impl Codec<String> for Text {
+///     fn encode(&self, value: &String) -> io::Result<Vec<u8>> {
+///         Ok(value.as_bytes().to_vec())
+///     }
+///
+///     fn decode(&self, bytes: &[u8]) -> io::Result<String> {
+///         String::from_utf8(bytes.to_vec()).map_err(|_| io::ErrorKind::InvalidData.into())
+///     }
+/// }
+///
+/// let path = std::env::temp_dir().join(format!("ibag-doc-{}.cfg", std::process::id()));
+/// let bag = PersistentBag::open(&path, Text, String::new).unwrap();
+/// bag.with(|cfg| cfg.push_str("retries=3")).unwrap();
+/// drop(bag);
+///
+/// let bag = PersistentBag::open(&path, Text, String::new).unwrap();
+/// assert_eq!(*bag.load(), "retries=3");
+/// # drop(bag);
+/// # std::fs::remove_file(&path).unwrap();
+/// # std::fs::remove_file(path.with_extension("cfg.wal")).unwrap();
+/// ```
+pub struct PersistentBag<T> {
+    bag: iBag<T>,
+    store: Arc<Store<T>>,
+    debouncer: Option<JoinHandle<()>>,
+}
+
+impl<T> PersistentBag<T> {
+    /// Opens the bag stored at `path`, snapshotting after every write
+    ///
+    /// Recovers the newest consistent value from the snapshot and the log,
+    /// or starts from `default()` and saves it if neither exists.
+    ///
+    /// # Errors
+    /// If the files cannot be read or written, if the codec fails, or with
+    /// `InvalidData` if the snapshot is corrupt and the log cannot replace
+    /// it.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn open<C, F>(path: impl AsRef<Path>, codec: C, default: F) -> io::Result<Self>
+    where
+        C: Codec<T> + 'static,
+        F: FnOnce() -> T,
+    {
+        let (bag, store) = Self::recover(path.as_ref(), Box::new(codec), default)?;
+        Ok(PersistentBag { bag, store, debouncer: None })
+    }
+
+    /// Opens the bag stored at `path`, snapshotting at most once per
+    /// `interval`
+    ///
+    /// Writes are still logged one by one, so none is lost in a crash; the
+    /// interval only bounds how much log there is to replay. The last
+    /// snapshot is taken when the bag is dropped.
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    pub fn open_debounced<C, F>(path: impl AsRef<Path>, codec: C, default: F, interval: Duration) -> io::Result<Self>
+    where
+        T: 'static,
+        C: Codec<T> + 'static,
+        F: FnOnce() -> T,
+    {
+        let (bag, store) = Self::recover(path.as_ref(), Box::new(codec), default)?;
+        let debounced = store.clone();
+        let debouncer = thread::Builder::new()
+            .name("ibag-persist".into())
+            .spawn(move || debounced.run_debounced(interval))?;
+        Ok(PersistentBag { bag, store, debouncer: Some(debouncer) })
+    }
+
+    #[cfg_attr(feature = "deadlock-detection", track_caller)]
+    fn recover<F>(path: &Path, codec: Box<dyn Codec<T>>, default: F) -> io::Result<(iBag<T>, Arc<Store<T>>)>
+    where
+        F: FnOnce() -> T,
+    {
+        let log_path = sibling(path, ".wal");
+        let snapshot = read_file(path)?;
+        let log = read_file(&log_path)?.unwrap_or_default();
+
+        let saved = snapshot.as_deref().map(parse_snapshot);
+        let logged = last_record(&log);
+        let (seq, payload, snapshot_seq) = match (saved, logged) {
+            (Some(Some((seq, _))), Some((logged, newer))) if logged > seq => (logged, newer.to_vec(), seq),
+            (Some(Some((seq, payload))), _) => (seq, payload.to_vec(), seq),
+            (Some(None), Some((logged, newer))) => (logged, newer.to_vec(), 0),
+            (Some(None), None) => return Err(invalid_data("corrupt snapshot and no log to recover from")),
+            (None, Some((logged, newer))) => (logged, newer.to_vec(), 0),
+            (None, None) => (1, codec.encode(&default())?, 0),
+        };
+        let value = codec.decode(&payload)?;
+
+        let log = OpenOptions::new().create(true).append(true).open(&log_path)?;
+        let store = Store {
+            codec,
+            path: path.to_path_buf(),
+            state: Mutex::new(State {
+                log: Box::new(log),
+                seq,
+                latest: payload.into(),
+                snapshot_seq,
+                dirty: false,
+                closed: false,
+                broken: false,
+            }),
+            wake: Condvar::new(),
+            writing: Mutex::new(()),
+        };
+        // Fold what was recovered from the log, or the default, into a
+        // fresh snapshot and start with an empty log.
+        store.snapshot()?;
+        {
+            let mut state = store.state();
+            state.log.truncate(0)?;
+            state.log.sync()?;
+        }
+        let bag = iBag::new(value);
+        Ok((bag, Arc::new(store)))
+    }
+
+    /// Returns the path of the snapshot file
+    pub fn path(&self) -> &Path {
+        &self.store.path
+    }
+
+    /// Acquires a read lock, see `iBag::load`
+    pub fn load(&self) -> ReadGuard<'_, T> {
+        self.bag.load()
+    }
+
+    /// Executes a closure with read-only access, see `iBag::with_read`
+    pub fn with_read<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.bag.with_read(f)
+    }
+
+    /// Returns a read-only handle that sees every persisted write
+    pub fn reader(&self) -> iBagReader<T> {
+        self.bag.reader()
+    }
+
+    /// Changes the value and makes the change durable
+    ///
+    /// The new value is logged and synced before the write lock is
+    /// released. If that fails, the value is rolled back to the last
+    /// durable one and the error is returned; readers never see a value
+    /// that was not saved. A snapshot that fails after the log succeeded
+    /// is retried later and not reported here.
+    ///
+    /// A partly written record is cut off the log again. If even that
+    /// fails, later writes fail as well until `flush` has written a
+    /// snapshot and emptied the log.
+    ///
+    /// # Panics
+    /// If the bag is poisoned, like `iBag::with`.
+    pub fn with<F, R>(&self, f: F) -> io::Result<R>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let mut guard = self.bag.write();
+        let result = f(&mut guard);
+        if let Err(err) = self.store.append(&guard) {
+            // The value can only stay changed if the saved one no longer
+            // decodes, which the error still reports.
+            if let Ok(saved) = self.store.latest() {
+                *guard = saved;
+                guard.mark_unchanged();
+            }
+            return Err(err);
+        }
+        drop(guard);
+        if self.debouncer.is_none() {
+            let _ = self.store.snapshot();
+        }
+        Ok(result)
+    }
+
+    /// Writes a snapshot of the current value now, if the last one is
+    /// out of date
+    pub fn flush(&self) -> io::Result<()> {
+        self.store.snapshot()
+    }
+}
+
+impl<T> Drop for PersistentBag<T> {
+    fn drop(&mut self) {
+        if let Some(debouncer) = self.debouncer.take() {
+            self.store.state().closed = true;
+            self.store.wake.notify_one();
+            let _ = debouncer.join();
+        }
+        // Retries a snapshot that failed after the last write.
+        let _ = self.store.snapshot();
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PersistentBag<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PersistentBag")
+            .field("path", &self.store.path)
+            .field("bag", &self.bag)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_log_stops_at_torn_record() {
+        let mut log = record(1, b"one");
+        log.extend(record(2, b"two"));
+        assert_eq!(last_record(&log), Some((2, &b"two"[..])));
+
+        let mut torn = log.clone();
+        torn.extend(&record(3, b"three")[..10]);
+        assert_eq!(last_record(&torn), Some((2, &b"two"[..])));
+
+        let mut corrupt = log.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert_eq!(last_record(&corrupt), Some((1, &b"one"[..])));
+        assert_eq!(last_record(&[]), None);
+    }
+
+    #[test]
+    fn test_snapshot_format() {
+        let dir = std::env::temp_dir().join(format!("ibag-snapshot-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("value");
+        write_snapshot(&path, 7, b"payload").unwrap();
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(parse_snapshot(&bytes), Some((7, &b"payload"[..])));
+        assert_eq!(parse_snapshot(&bytes[..bytes.len() - 1]), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct Bytes;
+
+    impl Codec<Vec<u8>> for Bytes {
+        fn encode(&self, value: &Vec<u8>) -> io::Result<Vec<u8>> {
+            Ok(value.clone())
+        }
+
+        fn decode(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(bytes.to_vec())
+        }
+    }
+
+    /// A log that can be told to tear its writes or fail to truncate
+    struct Flaky {
+        file: File,
+        tear: Arc<AtomicBool>,
+        stuck: Arc<AtomicBool>,
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.tear.load(Ordering::Relaxed) {
+                self.file.write_all(&buf[..buf.len() / 2])?;
+                return Err(io::Error::other("disk full"));
+            }
+            self.file.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Log for Flaky {
+        fn size(&mut self) -> io::Result<u64> {
+            self.file.size()
+        }
+
+        fn truncate(&mut self, len: u64) -> io::Result<()> {
+            if self.stuck.load(Ordering::Relaxed) {
+                return Err(io::Error::other("read-only"));
+            }
+            self.file.truncate(len)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.file.sync()
+        }
+    }
+
+    /// Opens a bag that is never snapshotted, so every write stays in the
+    /// log, and swaps in a flaky log
+    fn flaky_bag(path: &Path) -> (PersistentBag<Vec<u8>>, Arc<AtomicBool>, Arc<AtomicBool>) {
+        let bag = PersistentBag::open_debounced(path, Bytes, Vec::new, Duration::from_secs(3600)).unwrap();
+        let (tear, stuck) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)));
+        let file = OpenOptions::new().append(true).open(sibling(path, ".wal")).unwrap();
+        bag.store.state().log = Box::new(Flaky { file, tear: tear.clone(), stuck: stuck.clone() });
+        (bag, tear, stuck)
+    }
+
+    /// Recovers the value from the log alone, as after a crash
+    fn recovered(path: &Path) -> Vec<u8> {
+        let log = fs::read(sibling(path, ".wal")).unwrap();
+        last_record(&log).map(|(_, payload)| payload.to_vec()).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_failed_append_is_cut_off() {
+        let dir = std::env::temp_dir().join(format!("ibag-flaky-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("value");
+        let (bag, tear, _) = flaky_bag(&path);
+        bag.with(|v| v.push(1)).unwrap();
+
+        tear.store(true, Ordering::Relaxed);
+        assert_eq!(bag.with(|v| v.push(2)).unwrap_err().to_string(), "disk full");
+        assert_eq!(*bag.load(), [1]);
+
+        // Without the cut, the torn record would end the log here.
+        tear.store(false, Ordering::Relaxed);
+        bag.with(|v| v.push(3)).unwrap();
+        assert_eq!(recovered(&path), [1, 3]);
+        drop(bag);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_unusable_if_cut_fails() {
+        let dir = std::env::temp_dir().join(format!("ibag-stuck-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("value");
+        let (bag, tear, stuck) = flaky_bag(&path);
+        bag.with(|v| v.push(1)).unwrap();
+
+        tear.store(true, Ordering::Relaxed);
+        stuck.store(true, Ordering::Relaxed);
+        assert!(bag.with(|v| v.push(2)).is_err());
+        tear.store(false, Ordering::Relaxed);
+        assert!(bag.with(|v| v.push(3)).is_err());
+        assert_eq!(*bag.load(), [1]);
+        assert_eq!(recovered(&path), [1]);
+
+        // A snapshot covers the log, after which it can be emptied.
+        stuck.store(false, Ordering::Relaxed);
+        bag.flush().unwrap();
+        bag.with(|v| v.push(4)).unwrap();
+        drop(bag);
+        let bag = PersistentBag::open(&path, Bytes, Vec::new).unwrap();
+        assert_eq!(*bag.load(), [1, 4]);
+        drop(bag);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
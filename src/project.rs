@@ -0,0 +1,154 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Read-only views of a part of the value in an `iBag`.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::bag::{iBag, MappedReadGuard, ReadGuard};
+
+/// A read-only view of a part of the value in an iBag
+///
+/// Created by `iBag::project()`. The view holds a handle to the bag, not a
+/// lock; every read locks the bag and applies the projection again.
+pub struct Projection<T, U: ?Sized, F> {
+    bag: iBag<T>,
+    project: F,
+    _marker: PhantomData<fn(&T) -> &U>,
+}
+
+impl<T, U: ?Sized, F> Projection<T, U, F>
+where
+    F: Fn(&T) -> &U,
+{
+    pub(crate) fn new(bag: iBag<T>, project: F) -> Self {
+        Projection {
+            bag,
+            project,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Acquires a read lock on the bag and returns a guard over the part
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new((1, 2));
+    /// let second = bag.project(|pair: &(i32, i32)| &pair.1);
+    /// assert_eq!(*second.load(), 2);
+    /// ```
+    pub fn load(&self) -> MappedReadGuard<'_, U> {
+        ReadGuard::map(self.bag.load(), &self.project)
+    }
+
+    /// Executes a closure with read-only access to the part
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(vec![1, 2, 3]);
+    /// let tail = bag.project(|v: &Vec<i32>| &v[1..]);
+    /// assert_eq!(tail.with_read(|t| t.len()), 2);
+    /// ```
+    pub fn with_read<R, G>(&self, f: G) -> R
+    where
+        G: FnOnce(&U) -> R,
+    {
+        f(&self.load())
+    }
+}
+
+impl<T, U: ?Sized, F: Clone> Clone for Projection<T, U, F> {
+    fn clone(&self) -> Self {
+        Projection {
+            bag: self.bag.clone(),
+            project: self.project.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, U, F> fmt::Debug for Projection<T, U, F>
+where
+    U: ?Sized + fmt::Debug,
+    F: Fn(&T) -> &U,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Projection")
+            .field("data", &&*self.load())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{iBag, MappedReadGuard, MappedWriteGuard, ReadGuard, WriteGuard};
+
+    struct Outer {
+        inner: Inner,
+        tag: &'static str,
+    }
+
+    struct Inner {
+        values: Vec<u32>,
+    }
+
+    fn values(bag: &iBag<Outer>) -> MappedReadGuard<'_, [u32]> {
+        let inner = ReadGuard::map(bag.load(), |o| &o.inner);
+        MappedReadGuard::map(inner, |i| i.values.as_slice())
+    }
+
+    fn bag() -> iBag<Outer> {
+        iBag::new(Outer {
+            inner: Inner { values: vec![1, 2] },
+            tag: "t",
+        })
+    }
+
+    #[test]
+    fn test_mapped_read_guard_holds_lock() {
+        let bag = bag();
+        let guard = values(&bag);
+        assert_eq!(&*guard, &[1, 2]);
+        assert!(bag.try_write_now().is_err());
+        drop(guard);
+        assert!(bag.try_write_now().is_ok());
+    }
+
+    #[test]
+    fn test_mapped_write_guard() {
+        let bag = bag();
+        let inner = WriteGuard::map(bag.write(), |o| &mut o.inner);
+        let mut values = MappedWriteGuard::map(inner, |i| &mut i.values);
+        values.push(3);
+        assert!(bag.try_load_now().is_err());
+        drop(values);
+        assert_eq!(bag.load().inner.values, vec![1, 2, 3]);
+        assert_eq!(bag.version(), 1);
+    }
+
+    #[test]
+    fn test_try_map_returns_original() {
+        let bag = bag();
+        let guard = ReadGuard::try_map(bag.load(), |o| o.inner.values.get(5)).unwrap_err();
+        assert_eq!(guard.tag, "t");
+        drop(guard);
+        let guard = WriteGuard::try_map(bag.write(), |o| o.inner.values.get_mut(5)).unwrap_err();
+        assert_eq!(guard.tag, "t");
+    }
+
+    #[test]
+    fn test_projection_follows_writes() {
+        let bag = bag();
+        let tag = bag.project(|o: &Outer| o.tag);
+        let values = bag.project(|o: &Outer| &o.inner.values);
+        bag.with(|o| {
+            o.tag = "u";
+            o.inner.values.clear();
+        });
+        assert_eq!(&*tag.load(), "u");
+        assert!(values.with_read(|v| v.is_empty()));
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! A process-wide list of live bags, for debugging.
+//!
+//! Bags opt in by having a name, given with `iBag::named` or
+//! `BagBuilder::name`. Such a bag is listed from creation until its last
+//! handle is dropped. `bags()` describes each one: its name, value type,
+//! current holders and waiting writers. `dump()` prints the same as a
+//! report. Unnamed bags stay out of the registry and do not track their
+//! holders, so they pay for none of this.
+//!
+//! Nothing here waits for a bag's lock. The registry and each bag's
+//! internal state are only locked for short bookkeeping, never while user
+//! code runs, so a report can be taken even while every bag is held or
+//! deadlocked. That makes it suitable for a dedicated debug thread woken by
+//! a signal handler; the handler itself must not call it, since building
+//! the report allocates.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread::ThreadId;
+use std::time::Duration;
+
+use crate::lock::RawLock;
+pub use crate::lock::LockKind;
+
+/// A registered lock, valid until it unregisters from its `Drop`
+struct Entry(*const RawLock);
+
+// Entries are only dereferenced with the registry locked, and a lock has to
+// take the registry lock to unregister before it is freed.
+unsafe impl Send for Entry {}
+
+static BAGS: Mutex<BTreeMap<u64, Entry>> = Mutex::new(BTreeMap::new());
+
+fn bags_locked() -> MutexGuard<'static, BTreeMap<u64, Entry>> {
+    BAGS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn register(id: u64, raw: &RawLock) {
+    bags_locked().insert(id, Entry(raw));
+}
+
+pub(crate) fn unregister(id: u64) {
+    bags_locked().remove(&id);
+}
+
+/// Runs `f`, which may move the lock registered as `id` out of its
+/// allocation, and unregisters the lock if it did
+///
+/// `id` is `None` for a lock that is not listed. Otherwise `f` must not run
+/// user code: the registry stays locked meanwhile.
+pub(crate) fn moving_out<R>(id: Option<u64>, f: impl FnOnce() -> Option<R>) -> Option<R> {
+    let Some(id) = id else {
+        return f();
+    };
+    let mut bags = bags_locked();
+    let moved = f();
+    if moved.is_some() {
+        bags.remove(&id);
+    }
+    moved
+}
+
+/// A hold on a bag and the thread that acquired it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Holder {
+    /// The kind of access held
+    pub kind: LockKind,
+    /// The thread that acquired the hold; guards may have moved since
+    pub thread: ThreadId,
+    /// The name of that thread, if it has one
+    pub thread_name: Option<String>,
+}
+
+/// What the registry knows about a live bag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BagInfo {
+    /// A process-wide unique id, also used by `deadlock-detection` reports
+    pub id: u64,
+    /// The name given with `iBag::named` or `BagBuilder::name`; always
+    /// set, since only named bags are listed
+    pub name: Option<String>,
+    /// The type of the value in the bag
+    pub type_name: &'static str,
+    /// Time since the bag was created
+    pub age: Duration,
+    /// Number of shared holds
+    pub readers: usize,
+    /// Whether a writer holds the bag
+    pub writer: bool,
+    /// Whether an upgradable reader holds the bag
+    pub upgradable: bool,
+    /// Number of writers waiting for the bag
+    pub waiting_writers: usize,
+    /// Every current hold
+    pub holders: Vec<Holder>,
+    /// The bag's version, see `iBag::version`
+    pub version: u64,
+    /// Whether the bag is poisoned
+    pub poisoned: bool,
+}
+
+impl BagInfo {
+    /// Returns `true` if anyone holds the bag
+    pub fn is_locked(&self) -> bool {
+        self.readers > 0 || self.writer || self.upgradable
+    }
+}
+
+impl fmt::Display for BagInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bag #{}", self.id)?;
+        if let Some(name) = &self.name {
+            write!(f, " {:?}", name)?;
+        }
+        write!(f, ": {}, age {:.1?}, version {}", self.type_name, self.age, self.version)?;
+        if self.poisoned {
+            write!(f, ", poisoned")?;
+        }
+        if !self.is_locked() {
+            write!(f, ", unlocked")?;
+        }
+        if self.waiting_writers > 0 {
+            write!(f, ", {} waiting writer(s)", self.waiting_writers)?;
+        }
+        for holder in &self.holders {
+            write!(f, "\n    {:?} held by {:?}", holder.kind, holder.thread)?;
+            if let Some(name) = &holder.thread_name {
+                write!(f, " ({})", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Describes every live named bag, oldest first
+///
+/// # Examples
+/// ```
+/// use ibag::{iBag, registry};
+///
+/// let bag = iBag::named("session-cache", vec![1, 2]);
+/// let _guard = bag.load();
+/// let info = registry::bags()
+///     .into_iter()
+///     .find(|info| info.name.as_deref() == Some("session-cache"))
+///     .unwrap();
+/// assert_eq!(info.readers, 1);
+/// assert_eq!(info.type_name, "alloc::vec::Vec<i32>");
+/// ```
+pub fn bags() -> Vec<BagInfo> {
+    let bags = bags_locked();
+    // The entry is registered, so the lock is still alive.
+    bags.values().map(|entry| unsafe { &*entry.0 }.info()).collect()
+}
+
+/// Returns a human-readable report of every live bag
+pub fn report() -> String {
+    let bags = bags();
+    let locked = bags.iter().filter(|info| info.is_locked()).count();
+    let mut report = format!("{} live bag(s), {} locked\n", bags.len(), locked);
+    for info in bags {
+        report.push_str(&info.to_string());
+        report.push('\n');
+    }
+    report
+}
+
+/// Prints `report()` to standard error
+pub fn dump() {
+    eprint!("{}", report());
+}
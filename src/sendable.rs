@@ -83,9 +83,9 @@ impl <T> Clone for SendableOption<T> {
     }
 }
 
-impl <T> Into<Option<Arc<Mutex<T>>>> for SendableOption<T> {
-    fn into(self) -> Option<Arc<Mutex<T>>> {
-        match self {
+impl <T> From<SendableOption<T>> for Option<Arc<Mutex<T>>> {
+    fn from(option: SendableOption<T>) -> Self {
+        match option {
             SendableOption::Some(arc) => Some(arc.clone()),
             SendableOption::None => None,
         }
@@ -185,9 +185,9 @@ impl <T,E> From<Result<T, E>> for SendableResult<T, E> {
     }
 }
 
-impl <T,E> Into<Result<Arc<Mutex<T>>, E>> for SendableResult<T, E> {
-    fn into(self) -> Result<Arc<Mutex<T>>, E> {
-        match self {
+impl <T,E> From<SendableResult<T, E>> for Result<Arc<Mutex<T>>, E> {
+    fn from(result: SendableResult<T, E>) -> Self {
+        match result {
             SendableResult::Ok(arc) => Ok(arc.clone()),
             SendableResult::Err(err) => Err(err),
         }
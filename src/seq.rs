@@ -0,0 +1,363 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! A sequence-locked bag for small `Copy` values.
+//!
+//! Writers take a mutex, make the sequence number odd, store the new value
+//! and make it even again. Readers copy the value between two loads of the
+//! sequence number and keep the copy only if neither load saw a writer, so
+//! they never store to shared memory and never wait for each other.
+//!
+//! The copies that can overlap go a word at a time through relaxed atomic
+//! loads and stores, as in crossbeam's `AtomicCell` fallback, so a reader
+//! racing with a writer gets a torn copy to throw away rather than a data
+//! race.
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::hint;
+use std::mem::{self, MaybeUninit};
+use std::sync::atomic::{self, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// A thread-safe bag for small `Copy` values with lock-free reads
+///
+/// Reads copy the whole value and retry if a write overlapped, so they
+/// suit values of a few words that are read far more often than written,
+/// such as coordinates, timestamps and counters. Writers are serialized
+/// and never wait for readers.
+///
+/// # Examples
+/// ```
+/// use ibag::SeqBag;
+/// use std::thread;
+///
+/// let position = SeqBag::new((0.0, 0.0));
+/// let p = position.clone();
+/// thread::spawn(move || p.with(|(x, y)| {
+///     *x += 1.0;
+///     *y += 2.0;
+/// }))
+/// .join()
+/// .unwrap();
+/// assert_eq!(position.load(), (1.0, 2.0));
+/// ```
+pub struct SeqBag<T: Copy> {
+    inner: Arc<SeqInner<T>>,
+}
+
+struct SeqInner<T> {
+    /// Odd while a writer is storing, bumped by two per write
+    seq: AtomicU64,
+    value: Slot<T>,
+    writer: Mutex<()>,
+}
+
+/// The value, aligned so that it can be copied a word at a time
+#[repr(C)]
+struct Slot<T> {
+    _align: [AtomicUsize; 0],
+    value: UnsafeCell<T>,
+}
+
+const WORD: usize = mem::size_of::<usize>();
+
+/// Copies the value out of `slot` with relaxed atomic loads
+///
+/// # Safety
+/// Every concurrent store to the slot must go through `atomic_store`.
+unsafe fn atomic_load<T>(slot: &Slot<T>) -> MaybeUninit<T> {
+    let src = slot.value.get() as *const u8;
+    let mut value = MaybeUninit::<T>::uninit();
+    let dst = value.as_mut_ptr() as *mut u8;
+    let mut i = 0;
+    while i + WORD <= mem::size_of::<T>() {
+        let word = (*(src.add(i) as *const AtomicUsize)).load(Ordering::Relaxed);
+        (dst.add(i) as *mut usize).write_unaligned(word);
+        i += WORD;
+    }
+    while i < mem::size_of::<T>() {
+        *dst.add(i) = (*(src.add(i) as *const AtomicU8)).load(Ordering::Relaxed);
+        i += 1;
+    }
+    value
+}
+
+/// Copies `value` into `slot` with relaxed atomic stores
+///
+/// # Safety
+/// Every concurrent load from the slot must go through `atomic_load`.
+unsafe fn atomic_store<T>(slot: &Slot<T>, value: &T) {
+    let src = value as *const T as *const u8;
+    let dst = slot.value.get() as *const u8;
+    let mut i = 0;
+    while i + WORD <= mem::size_of::<T>() {
+        let word = (src.add(i) as *const usize).read_unaligned();
+        (*(dst.add(i) as *const AtomicUsize)).store(word, Ordering::Relaxed);
+        i += WORD;
+    }
+    while i < mem::size_of::<T>() {
+        (*(dst.add(i) as *const AtomicU8)).store(*src.add(i), Ordering::Relaxed);
+        i += 1;
+    }
+}
+
+// Readers only ever get their own copies of the value, which may end up on
+// any thread; `T: Copy` rules out drops of torn copies.
+unsafe impl<T: Send> Send for SeqInner<T> {}
+unsafe impl<T: Send> Sync for SeqInner<T> {}
+
+impl<T: Copy> SeqBag<T> {
+    /// Creates a new SeqBag holding `value`
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SeqBag;
+    /// let bag = SeqBag::new(42u64);
+    /// ```
+    pub fn new(value: T) -> Self {
+        SeqBag {
+            inner: Arc::new(SeqInner {
+                seq: AtomicU64::new(0),
+                value: Slot {
+                    _align: [],
+                    value: UnsafeCell::new(value),
+                },
+                writer: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// Returns a copy of the value
+    ///
+    /// Spins while a writer is storing and retries if one overlapped the
+    /// copy.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SeqBag;
+    /// let bag = SeqBag::new([1u32, 2, 3]);
+    /// assert_eq!(bag.load(), [1, 2, 3]);
+    /// ```
+    pub fn load(&self) -> T {
+        let inner = &*self.inner;
+        loop {
+            let before = inner.seq.load(Ordering::Acquire);
+            if before & 1 == 0 {
+                // SAFETY: writers store through `atomic_store`, so this copy
+                // may be torn but is not a data race. It stays uninterpreted
+                // until the sequence number shows that no writer ran
+                // meanwhile. Padding bytes of `T` are read as integers, the
+                // same caveat `AtomicCell` and the seqlock crates live with.
+                let value = unsafe { atomic_load(&inner.value) };
+                atomic::fence(Ordering::Acquire);
+                if inner.seq.load(Ordering::Relaxed) == before {
+                    return unsafe { value.assume_init() };
+                }
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// Executes a closure with read-only access to a copy of the value
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SeqBag;
+    /// let bag = SeqBag::new((3, 4));
+    /// assert_eq!(bag.with_read(|(x, y)| x * x + y * y), 25);
+    /// ```
+    pub fn with_read<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        f(&self.load())
+    }
+
+    /// Executes a closure with mutable access to the value
+    ///
+    /// Other writers wait until the closure returns. It runs on a copy, so
+    /// readers keep seeing the old value meanwhile and only retry while
+    /// the result is stored. A panicking closure leaves the value
+    /// unchanged.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SeqBag;
+    /// let hits = SeqBag::new(0u64);
+    /// let total = hits.with(|n| {
+    ///     *n += 1;
+    ///     *n
+    /// });
+    /// assert_eq!(total, 1);
+    /// ```
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let inner = &*self.inner;
+        // Nothing is stored while a closure runs, so a panic cannot leave
+        // a torn value behind.
+        let _writer = inner.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        // Writers are serialized, so nothing can change the value under us.
+        let mut value = unsafe { *inner.value.value.get() };
+        let result = f(&mut value);
+        let seq = inner.seq.load(Ordering::Relaxed);
+        inner.seq.store(seq + 1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+        // SAFETY: readers load through `atomic_load`, and writers are
+        // serialized by the mutex.
+        unsafe { atomic_store(&inner.value, &value) };
+        inner.seq.store(seq + 2, Ordering::Release);
+        result
+    }
+
+    /// Replaces the value
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SeqBag;
+    /// let bag = SeqBag::new(1);
+    /// bag.store(2);
+    /// assert_eq!(bag.load(), 2);
+    /// ```
+    pub fn store(&self, value: T) {
+        self.with(|v| *v = value);
+    }
+
+    /// Returns the number of completed writes
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SeqBag;
+    /// let bag = SeqBag::new(1);
+    /// bag.store(2);
+    /// assert_eq!(bag.version(), 1);
+    /// ```
+    pub fn version(&self) -> u64 {
+        self.inner.seq.load(Ordering::Acquire) / 2
+    }
+}
+
+impl<T: Copy> Clone for SeqBag<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Copy + Default> Default for SeqBag<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Copy> From<T> for SeqBag<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for SeqBag<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SeqBag").field("data", &self.load()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn test_no_torn_reads() {
+        let bag = SeqBag::new([0u64; 16]);
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for n in 1..=20_000u64 {
+                        bag.store([n; 16]);
+                    }
+                });
+            }
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut reads = 0;
+                    while !done.load(Ordering::Relaxed) || reads == 0 {
+                        let value = bag.load();
+                        assert!(value.iter().all(|&word| word == value[0]), "torn read: {:?}", value);
+                        bag.with_read(|value| assert!(value.iter().all(|&word| word == value[0])));
+                        reads += 1;
+                    }
+                });
+            }
+            // Let the writers finish, then stop the readers.
+            s.spawn(|| {
+                while bag.version() < 40_000 {
+                    thread::yield_now();
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+        });
+        assert_eq!(bag.version(), 40_000);
+    }
+
+    #[test]
+    fn test_writers_are_serialized() {
+        let bag = SeqBag::new((0u64, 0u64));
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        bag.with(|(a, b)| {
+                            *a += 1;
+                            *b = *a * 2;
+                        });
+                    }
+                });
+            }
+        });
+        assert_eq!(bag.load(), (8000, 16000));
+        assert_eq!(bag.version(), 8000);
+    }
+
+    #[test]
+    fn test_panicking_writer_changes_nothing() {
+        let bag = SeqBag::new(1);
+        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+            bag.with(|v| {
+                *v = 2;
+                panic!("boom");
+            })
+        }));
+        assert!(r.is_err());
+        assert_eq!((bag.load(), bag.version()), (1, 0));
+        bag.store(3);
+        assert_eq!(bag.load(), 3);
+    }
+
+    #[test]
+    fn test_copies_partial_words() {
+        let bag = SeqBag::new([1u8; 11]);
+        bag.store([2; 11]);
+        assert_eq!(bag.load(), [2; 11]);
+        let bag = SeqBag::new((1u16, 2u64, true));
+        bag.with(|v| v.0 = 3);
+        assert_eq!(bag.load(), (3, 2, true));
+        assert_eq!(SeqBag::new(()).load(), ());
+    }
+
+    #[test]
+    fn test_clones_share_the_value() {
+        let bag = SeqBag::new('a');
+        let other = bag.clone();
+        other.store('b');
+        assert_eq!(bag.load(), 'b');
+        assert_eq!(format!("{:?}", bag), "SeqBag { data: 'b' }");
+        assert_eq!(SeqBag::<u8>::default().load(), 0);
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! `Serialize` and `Deserialize` behind the `serde` feature.
+//!
+//! Every container is transparent: it is written as the value it holds, and
+//! read back from that value. Only the value is kept; a bag's name, policy
+//! and history, or a cell's owner and freeze state, start afresh.
+
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Error, Serialize, Serializer};
+
+use crate::bag::iBag;
+use crate::cell::iCell;
+use crate::errors::InvalidThreadAccess;
+use crate::sendable::{SendableOption, SendableResult};
+
+/// Serializes the value under a read lock
+///
+/// Fails if the bag is poisoned.
+impl<T: Serialize> Serialize for iBag<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.try_load() {
+            Ok(guard) => guard.serialize(serializer),
+            Err(err) => Err(S::Error::custom(err)),
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for iBag<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(iBag::new)
+    }
+}
+
+/// Serializes the value, which only the owning thread may access
+///
+/// Fails with the `InvalidThreadAccess` message on any other thread; use
+/// `iCell::try_serialize` to get the error itself.
+impl<T: Serialize> Serialize for iCell<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.try_get() {
+            Ok(value) => value.serialize(serializer),
+            Err(err) => Err(S::Error::custom(err)),
+        }
+    }
+}
+
+impl<T: Serialize> iCell<T> {
+    /// Serializes the value, reporting a foreign thread as a matchable error
+    ///
+    /// `Serialize` can only hand `InvalidThreadAccess` to the serializer as
+    /// a message; this checks the thread before the serializer is touched.
+    ///
+    /// # Returns
+    /// - `Ok(result)` with the serializer's result on the owning thread
+    /// - `Err(InvalidThreadAccess)` on any other thread
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iCell;
+    /// let cell = iCell::new(42, false);
+    /// let mut json = Vec::new();
+    /// let result = cell.try_serialize(&mut serde_json::Serializer::new(&mut json));
+    /// assert!(matches!(result, Ok(Ok(()))));
+    /// assert_eq!(json, b"42");
+    /// ```
+    pub fn try_serialize<S: Serializer>(&self, serializer: S) -> Result<Result<S::Ok, S::Error>, InvalidThreadAccess> {
+        Ok(self.try_get()?.serialize(serializer))
+    }
+}
+
+/// Deserializes into a cell owned by the current thread
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for iCell<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(|value| iCell::new(value, false))
+    }
+}
+
+/// Serializes like an `Option<T>`, locking the inner mutex
+///
+/// Fails if the mutex is poisoned.
+impl<T: Serialize> Serialize for SendableOption<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            SendableOption::Some(value) => {
+                let value = value.lock().map_err(S::Error::custom)?;
+                serializer.serialize_some(&*value)
+            }
+            SendableOption::None => serializer.serialize_none(),
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for SendableOption<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Option::<T>::deserialize(deserializer).map(SendableOption::from)
+    }
+}
+
+/// Serializes like a `Result<T, E>`, locking the inner mutex
+///
+/// Fails if the mutex is poisoned.
+impl<T: Serialize, E: Serialize> Serialize for SendableResult<T, E> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            SendableResult::Ok(value) => {
+                let value = value.lock().map_err(S::Error::custom)?;
+                Ok::<&T, &E>(&*value).serialize(serializer)
+            }
+            SendableResult::Err(err) => Err::<&T, &E>(err).serialize(serializer),
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>, E: Deserialize<'de>> Deserialize<'de> for SendableResult<T, E> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Result::<T, E>::deserialize(deserializer).map(SendableResult::from)
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Consistent reads spanning several bags.
+//!
+//! All bags are read-locked before the closure runs and released after it
+//! returns, so it sees every value as of one instant: no writer can get in
+//! between. The locks are taken in the same global order as `txn` uses, so
+//! snapshots and transactions over overlapping bags cannot deadlock.
+
+use crate::bag::{iBag, ReadGuard};
+use crate::txn::lock_order;
+
+/// A set of bags that can be read together by `snapshot`
+///
+/// Implemented for tuples of up to eight `&iBag` of any types, for arrays
+/// and for slices of `&iBag` of one type. The closure receives a tuple of
+/// references, an array of them or a slice of them respectively.
+pub trait SnapshotSet<F, R> {
+    /// Read-locks every bag and runs `f`
+    fn run(self, f: F) -> R;
+}
+
+/// Runs `f` with read access to several bags at one logical instant
+///
+/// # Panics
+/// Panics if the same bag appears twice, or if any bag is poisoned.
+///
+/// # Examples
+/// ```
+/// use ibag::{iBag, snapshot};
+///
+/// let orders = iBag::new(vec![40, 2]);
+/// let total = iBag::new(42);
+/// let name = iBag::new(String::from("daily"));
+///
+/// let line = snapshot((&orders, &total, &name), |(orders, total, name)| {
+///     assert_eq!(orders.iter().sum::<i32>(), *total);
+///     format!("{}: {}", name, total)
+/// });
+/// assert_eq!(line, "daily: 42");
+///
+/// let shards = [iBag::new(1), iBag::new(2), iBag::new(3)];
+/// let refs: Vec<_> = shards.iter().collect();
+/// assert_eq!(snapshot(&refs[..], |values| values.iter().copied().sum::<i32>()), 6);
+/// ```
+pub fn snapshot<S, F, R>(bags: S, f: F) -> R
+where
+    S: SnapshotSet<F, R>,
+{
+    bags.run(f)
+}
+
+/// Read-locks all bags of one type in global order, returning guards in
+/// the caller's order
+fn read_all<'b, T>(bags: &[&'b iBag<T>]) -> Vec<ReadGuard<'b, T>> {
+    let addrs: Vec<usize> = bags.iter().map(|bag| bag.addr()).collect();
+    let mut guards: Vec<Option<ReadGuard<'b, T>>> = bags.iter().map(|_| None).collect();
+    for i in lock_order(&addrs) {
+        guards[i] = Some(bags[i].load());
+    }
+    guards.into_iter().map(Option::unwrap).collect()
+}
+
+impl<T, F, R> SnapshotSet<F, R> for &[&iBag<T>]
+where
+    F: FnOnce(&[&T]) -> R,
+{
+    fn run(self, f: F) -> R {
+        let guards = read_all(self);
+        let refs: Vec<&T> = guards.iter().map(|guard| &**guard).collect();
+        f(&refs)
+    }
+}
+
+impl<T, F, R, const N: usize> SnapshotSet<F, R> for &[&iBag<T>; N]
+where
+    F: FnOnce([&T; N]) -> R,
+{
+    fn run(self, f: F) -> R {
+        let guards = read_all(self);
+        match guards.iter().map(|guard| &**guard).collect::<Vec<&T>>().try_into() {
+            Ok(refs) => f(refs),
+            Err(_) => unreachable!("one reference per bag"),
+        }
+    }
+}
+
+macro_rules! tuple_snapshot {
+    ($($T:ident $guard:ident $idx:tt),+) => {
+        impl<'b, $($T,)+ F, R> SnapshotSet<F, R> for ($(&'b iBag<$T>,)+)
+        where
+            F: FnOnce(($(&$T,)+)) -> R,
+        {
+            fn run(self, f: F) -> R {
+                $(let mut $guard = None;)+
+                for i in lock_order(&[$(self.$idx.addr()),+]) {
+                    match i {
+                        $($idx => $guard = Some(self.$idx.load()),)+
+                        _ => unreachable!(),
+                    }
+                }
+                $(let $guard = $guard.unwrap();)+
+                f(($(&*$guard,)+))
+            }
+        }
+    };
+}
+
+tuple_snapshot!(A a 0);
+tuple_snapshot!(A a 0, B b 1);
+tuple_snapshot!(A a 0, B b 1, C c 2);
+tuple_snapshot!(A a 0, B b 1, C c 2, D d 3);
+tuple_snapshot!(A a 0, B b 1, C c 2, D d 3, G g 4);
+tuple_snapshot!(A a 0, B b 1, C c 2, D d 3, G g 4, H h 5);
+tuple_snapshot!(A a 0, B b 1, C c 2, D d 3, G g 4, H h 5, I i 6);
+tuple_snapshot!(A a 0, B b 1, C c 2, D d 3, G g 4, H h 5, I i 6, J j 7);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::txn::txn;
+    use std::thread;
+
+    #[test]
+    fn test_sees_one_instant() {
+        let a = iBag::new(1000i64);
+        let b = iBag::new(1000i64);
+        thread::scope(|s| {
+            for n in 0..4 {
+                let (a, b) = (&a, &b);
+                s.spawn(move || {
+                    for _ in 0..500 {
+                        let r: Result<(), ()> = if n % 2 == 0 {
+                            txn((a, b), |(a, b)| {
+                                *a -= 1;
+                                *b += 1;
+                                Ok(())
+                            })
+                        } else {
+                            txn(&[b, a], |[b, a]| {
+                                *b -= 1;
+                                *a += 1;
+                                Ok(())
+                            })
+                        };
+                        r.unwrap();
+                    }
+                });
+            }
+            for n in 0..4 {
+                let (a, b) = (&a, &b);
+                s.spawn(move || {
+                    for _ in 0..500 {
+                        let sum = if n % 2 == 0 {
+                            snapshot((a, b), |(a, b)| a + b)
+                        } else {
+                            snapshot(&[b, a], |[b, a]| a + b)
+                        };
+                        assert_eq!(sum, 2000);
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_slices_keep_the_callers_order() {
+        let bags: Vec<_> = (0..5).map(iBag::new).collect();
+        let mut refs: Vec<_> = bags.iter().collect();
+        refs.reverse();
+        assert_eq!(snapshot(&refs[..], |values| values.iter().map(|v| **v).collect::<Vec<_>>()), [4, 3, 2, 1, 0]);
+        assert_eq!(snapshot(&[&bags[2], &bags[0]], |[x, y]| (*x, *y)), (2, 0));
+        assert_eq!(snapshot(&refs[..0], |values| values.len()), 0);
+    }
+
+    #[test]
+    fn test_holds_off_writers() {
+        let bag = iBag::new(0);
+        let other = iBag::new(0);
+        snapshot((&bag, &other), |_| {
+            assert!(bag.try_write_now().is_err());
+            assert!(bag.try_load_now().is_ok());
+        });
+        assert!(bag.try_write_now().is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "passed twice")]
+    fn test_duplicate_bag() {
+        let a = iBag::new(1);
+        snapshot((&a, &a.clone()), |_| ());
+    }
+}
@@ -0,0 +1,328 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! A read-mostly bag whose readers never take a lock.
+//!
+//! `SwapBag` keeps its value behind an `Arc` and publishes new values by
+//! swapping the pointer (read-copy-update). Readers pin the current epoch,
+//! clone the `Arc` and leave; a writer that retires a value waits until the
+//! readers pinned to the old epoch are gone before it gives up its reference.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+/// A thread-safe bag with lock-free snapshot reads
+///
+/// `load()` returns an `Arc<T>` snapshot without taking a lock, so readers
+/// never block each other or wait for writers. Writers publish whole new
+/// values with `store`, `swap`, `rcu` or `compare_and_swap` and are
+/// serialized among themselves.
+pub struct SwapBag<T> {
+    inner: Arc<SwapInner<T>>,
+}
+
+struct SwapInner<T> {
+    /// Raw pointer obtained from `Arc::into_raw`, owning one strong count
+    ptr: AtomicPtr<T>,
+    /// Readers currently between loading `ptr` and bumping its count,
+    /// split by the parity of the epoch they pinned
+    readers: [AtomicUsize; 2],
+    epoch: AtomicUsize,
+    writer: Mutex<()>,
+    _marker: PhantomData<Arc<T>>,
+}
+
+impl<T> SwapBag<T> {
+    /// Creates a new SwapBag holding the given value
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SwapBag;
+    /// let bag = SwapBag::new(42);
+    /// ```
+    pub fn new(value: T) -> Self {
+        Self::from_arc(Arc::new(value))
+    }
+
+    /// Creates a new SwapBag publishing an existing `Arc`
+    pub fn from_arc(value: Arc<T>) -> Self {
+        Self {
+            inner: Arc::new(SwapInner {
+                ptr: AtomicPtr::new(Arc::into_raw(value) as *mut T),
+                readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+                epoch: AtomicUsize::new(0),
+                writer: Mutex::new(()),
+                _marker: PhantomData,
+            }),
+        }
+    }
+
+    /// Returns a snapshot of the current value without taking a lock
+    ///
+    /// The snapshot stays valid after later writes; it simply stops being
+    /// the current value.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SwapBag;
+    /// let bag = SwapBag::new(42);
+    /// let snapshot = bag.load();
+    /// bag.store(100);
+    /// assert_eq!(*snapshot, 42);
+    /// assert_eq!(*bag.load(), 100);
+    /// ```
+    pub fn load(&self) -> Arc<T> {
+        let inner = &*self.inner;
+        let slot = loop {
+            let epoch = inner.epoch.load(Ordering::SeqCst);
+            let slot = &inner.readers[epoch & 1];
+            slot.fetch_add(1, Ordering::SeqCst);
+            // A writer that flipped the epoch meanwhile may already have
+            // stopped waiting on this slot, so pin the new epoch instead.
+            if inner.epoch.load(Ordering::SeqCst) == epoch {
+                break slot;
+            }
+            slot.fetch_sub(1, Ordering::SeqCst);
+        };
+        let ptr = inner.ptr.load(Ordering::SeqCst);
+        // The writer retiring `ptr` waits for this slot to drain, so the
+        // count it owns is still alive here.
+        let snapshot = unsafe {
+            Arc::increment_strong_count(ptr);
+            Arc::from_raw(ptr)
+        };
+        slot.fetch_sub(1, Ordering::SeqCst);
+        snapshot
+    }
+
+    /// Executes a closure with read-only access to the current value
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SwapBag;
+    /// let bag = SwapBag::new(42);
+    /// assert_eq!(bag.with_read(|val| *val), 42);
+    /// ```
+    pub fn with_read<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        f(&self.load())
+    }
+
+    /// Publishes a new value
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SwapBag;
+    /// let bag = SwapBag::new(42);
+    /// bag.store(100);
+    /// assert_eq!(*bag.load(), 100);
+    /// ```
+    pub fn store(&self, value: T) {
+        self.swap(value);
+    }
+
+    /// Publishes a new value and returns the previous one
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SwapBag;
+    /// let bag = SwapBag::new(42);
+    /// assert_eq!(*bag.swap(100), 42);
+    /// ```
+    pub fn swap(&self, value: T) -> Arc<T> {
+        let _writer = self.writer();
+        self.replace(Arc::new(value))
+    }
+
+    /// Publishes the value computed from the current one (read-copy-update)
+    ///
+    /// Writers are serialized, so `f` runs exactly once and no update made
+    /// through this bag can be lost. Returns the previous value.
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SwapBag;
+    /// let bag = SwapBag::new(vec![1, 2]);
+    /// bag.rcu(|old| {
+    ///     let mut new = old.clone();
+    ///     new.push(3);
+    ///     new
+    /// });
+    /// assert_eq!(*bag.load(), vec![1, 2, 3]);
+    /// ```
+    pub fn rcu<F>(&self, f: F) -> Arc<T>
+    where
+        F: FnOnce(&T) -> T,
+    {
+        let _writer = self.writer();
+        let current = unsafe { &*self.inner.ptr.load(Ordering::SeqCst) };
+        let value = f(current);
+        self.replace(Arc::new(value))
+    }
+
+    /// Publishes `new` only if `current` is still the current value
+    ///
+    /// `current` is compared by identity, so it should be a snapshot
+    /// obtained from `load()`.
+    ///
+    /// # Returns
+    /// - `Ok(previous)` if the value was replaced
+    /// - `Err(new)` if another writer published a value first
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::SwapBag;
+    /// let bag = SwapBag::new(1);
+    /// let snapshot = bag.load();
+    /// assert!(bag.compare_and_swap(&snapshot, 2).is_ok());
+    /// assert_eq!(bag.compare_and_swap(&snapshot, 3), Err(3));
+    /// assert_eq!(*bag.load(), 2);
+    /// ```
+    pub fn compare_and_swap(&self, current: &Arc<T>, new: T) -> Result<Arc<T>, T> {
+        let _writer = self.writer();
+        if !std::ptr::eq(self.inner.ptr.load(Ordering::SeqCst), Arc::as_ptr(current)) {
+            return Err(new);
+        }
+        Ok(self.replace(Arc::new(new)))
+    }
+
+    fn writer(&self) -> MutexGuard<'_, ()> {
+        self.inner.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Swaps in `value` and reclaims the old pointer's count once no reader
+    /// can still be about to use it. Must be called with the writer lock held.
+    fn replace(&self, value: Arc<T>) -> Arc<T> {
+        let inner = &*self.inner;
+        let old = inner.ptr.swap(Arc::into_raw(value) as *mut T, Ordering::SeqCst);
+        let epoch = inner.epoch.fetch_add(1, Ordering::SeqCst);
+        let slot = &inner.readers[epoch & 1];
+        while slot.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+        unsafe { Arc::from_raw(old) }
+    }
+}
+
+impl<T> Drop for SwapInner<T> {
+    fn drop(&mut self) {
+        unsafe { drop(Arc::from_raw(*self.ptr.get_mut())) };
+    }
+}
+
+impl<T> Clone for SwapBag<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Default> Default for SwapBag<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for SwapBag<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SwapBag<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SwapBag").field("data", &self.load()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    struct Counted(usize, Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.1.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_store_and_load() {
+        let bag = SwapBag::new(1);
+        let before = bag.load();
+        bag.store(2);
+        assert_eq!(*before, 1);
+        assert_eq!(*bag.load(), 2);
+        assert_eq!(*bag.swap(3), 2);
+        assert_eq!(bag.with_read(|v| *v), 3);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let bag = SwapBag::new(1);
+        let stale = bag.load();
+        bag.store(2);
+        assert_eq!(bag.compare_and_swap(&stale, 3), Err(3));
+        let current = bag.load();
+        assert_eq!(bag.compare_and_swap(&current, 3).map(|v| *v), Ok(2));
+        assert_eq!(*bag.load(), 3);
+    }
+
+    #[test]
+    fn test_concurrent_rcu() {
+        let bag = SwapBag::new(0usize);
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let bag = bag.clone();
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        bag.rcu(|v| v + 1);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*bag.load(), 4000);
+    }
+
+    #[test]
+    fn test_reclamation_under_concurrent_reads() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let bag = SwapBag::new(Counted(0, dropped.clone()));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let bag = bag.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    for _ in 0..20_000 {
+                        let seen = bag.load().0;
+                        assert!(seen >= last);
+                        last = seen;
+                    }
+                })
+            })
+            .collect();
+
+        for i in 1..=1000 {
+            bag.store(Counted(i, dropped.clone()));
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert_eq!(dropped.load(Ordering::SeqCst), 1000);
+        drop(bag);
+        assert_eq!(dropped.load(Ordering::SeqCst), 1001);
+    }
+}
@@ -0,0 +1,418 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Atomic updates spanning several bags.
+//!
+//! Bags taking part in a transaction are write-locked in the order of their
+//! shared allocation's address. Every handle to a bag agrees on that
+//! address, so two transactions over the same bags can never wait on each
+//! other in a cycle, whatever order the caller lists them in.
+
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::bag::{iBag, WriteGuard};
+use crate::errors::PoisonedBag;
+
+/// A set of bags that can be updated together by `txn`
+///
+/// Implemented for tuples of up to eight `&iBag` of any types, for arrays
+/// and for slices of `&iBag` of one type. The closure receives a tuple of
+/// `&mut` references, an array of them or a `&mut` slice of them
+/// respectively.
+pub trait TxnSet<F, R, E> {
+    /// Locks every bag, runs `f` and rolls back on failure
+    ///
+    /// Fails without running `f` if any bag is poisoned.
+    fn run(self, f: F) -> Result<Result<R, E>, PoisonedBag>;
+}
+
+/// Runs `f` with write access to several bags at once
+///
+/// All bags are locked in a global, stable order before `f` runs, so
+/// concurrent transactions cannot deadlock against each other. Every value
+/// is cloned first; if `f` returns `Err` or panics, all bags are restored to
+/// those pre-images before the locks are released and the error or panic is
+/// passed on. A rolled-back transaction does not bump the bags' versions.
+///
+/// # Panics
+/// Panics if the same bag appears twice, since it cannot be locked twice,
+/// or if any bag is poisoned. The other bags are released untouched before
+/// the panic, so they stay usable.
+///
+/// # Examples
+/// ```
+/// use ibag::{iBag, txn};
+///
+/// let from = iBag::new(100);
+/// let to = iBag::new(0);
+///
+/// let moved: Result<(), &str> = txn((&from, &to), |(from, to)| {
+///     *from -= 30;
+///     *to += 30;
+///     Ok(())
+/// });
+/// assert!(moved.is_ok());
+///
+/// let overdrawn = txn(&[&from, &to], |[from, to]| {
+///     *from -= 500;
+///     *to += 500;
+///     if *from < 0 { Err("insufficient funds") } else { Ok(()) }
+/// });
+/// assert_eq!(overdrawn, Err("insufficient funds"));
+/// assert_eq!((from.with_read(|v| *v), to.with_read(|v| *v)), (70, 30));
+/// ```
+pub fn txn<S, F, R, E>(bags: S, f: F) -> Result<R, E>
+where
+    S: TxnSet<F, R, E>,
+{
+    bags.run(f).unwrap()
+}
+
+/// Runs `f` with write access to several bags at once, failing instead of
+/// panicking when a bag is poisoned
+///
+/// Behaves like `txn` otherwise. The outer result is `Err` if any bag is
+/// poisoned; `f` has not run then, and no bag was changed.
+///
+/// # Examples
+/// ```
+/// use ibag::errors::PoisonedBag;
+/// use ibag::iBag;
+/// use ibag::txn::try_txn;
+/// use std::panic::{self, AssertUnwindSafe};
+///
+/// let healthy = iBag::new(1);
+/// let poisoned = iBag::new(2);
+/// let _ = panic::catch_unwind(AssertUnwindSafe(|| poisoned.with(|_| panic!("boom"))));
+///
+/// let r: Result<Result<(), ()>, _> = try_txn((&healthy, &poisoned), |(a, b)| {
+///     *a += *b;
+///     Ok(())
+/// });
+/// assert_eq!(r, Err(PoisonedBag));
+/// assert!(!healthy.is_poisoned());
+/// ```
+pub fn try_txn<S, F, R, E>(bags: S, f: F) -> Result<Result<R, E>, PoisonedBag>
+where
+    S: TxnSet<F, R, E>,
+{
+    bags.run(f)
+}
+
+/// Returns the indices of `addrs` in locking order
+///
+/// Panics if a bag appears twice.
+pub(crate) fn lock_order(addrs: &[usize]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..addrs.len()).collect();
+    order.sort_by_key(|&i| addrs[i]);
+    if order.windows(2).any(|w| addrs[w[0]] == addrs[w[1]]) {
+        panic!("the same bag was passed twice to a multi-bag operation");
+    }
+    order
+}
+
+/// Releases a guard without counting it as a write
+///
+/// Must not be called while panicking, or the bag would be poisoned.
+fn release<T>(mut guard: WriteGuard<'_, T>) {
+    guard.mark_unchanged();
+}
+
+/// Locks all bags of one type in global order, returning guards in the
+/// caller's order
+///
+/// If a bag is poisoned, the bags locked so far are released untouched.
+fn write_all<'b, T>(bags: &[&'b iBag<T>]) -> Result<Vec<WriteGuard<'b, T>>, PoisonedBag> {
+    let addrs: Vec<usize> = bags.iter().map(|bag| bag.addr()).collect();
+    let mut guards: Vec<Option<WriteGuard<'b, T>>> = bags.iter().map(|_| None).collect();
+    for i in lock_order(&addrs) {
+        match bags[i].try_write() {
+            Ok(guard) => guards[i] = Some(guard),
+            Err(err) => {
+                guards.into_iter().flatten().for_each(release);
+                return Err(err);
+            }
+        }
+    }
+    Ok(guards.into_iter().map(Option::unwrap).collect())
+}
+
+/// Runs `f` over the guarded values, restoring the pre-images on failure
+fn run_all<T, F, R, E>(mut guards: Vec<WriteGuard<'_, T>>, f: F) -> Result<R, E>
+where
+    T: Clone,
+    F: FnOnce(Vec<&mut T>) -> Result<R, E>,
+{
+    // A panicking clone must not poison the bags either.
+    let pre = panic::catch_unwind(AssertUnwindSafe(|| {
+        guards.iter().map(|guard| (**guard).clone()).collect::<Vec<T>>()
+    }));
+    let pre = match pre {
+        Ok(pre) => pre,
+        Err(payload) => {
+            guards.into_iter().for_each(release);
+            panic::resume_unwind(payload)
+        }
+    };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        f(guards.iter_mut().map(|guard| &mut **guard).collect())
+    }));
+    if !matches!(result, Ok(Ok(_))) {
+        for (guard, value) in guards.iter_mut().zip(pre) {
+            **guard = value;
+            guard.mark_unchanged();
+        }
+    }
+    // Release before resuming a panic so the restored bags are not poisoned.
+    drop(guards);
+    match result {
+        Ok(result) => result,
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
+
+impl<T, F, R, E> TxnSet<F, R, E> for &[&iBag<T>]
+where
+    T: Clone,
+    F: FnOnce(&mut [&mut T]) -> Result<R, E>,
+{
+    fn run(self, f: F) -> Result<Result<R, E>, PoisonedBag> {
+        Ok(run_all(write_all(self)?, |mut refs| f(&mut refs)))
+    }
+}
+
+impl<T, F, R, E, const N: usize> TxnSet<F, R, E> for &[&iBag<T>; N]
+where
+    T: Clone,
+    F: FnOnce([&mut T; N]) -> Result<R, E>,
+{
+    fn run(self, f: F) -> Result<Result<R, E>, PoisonedBag> {
+        Ok(run_all(write_all(self)?, |refs| match refs.try_into() {
+            Ok(refs) => f(refs),
+            Err(_) => unreachable!("one reference per bag"),
+        }))
+    }
+}
+
+macro_rules! tuple_txn {
+    ($($T:ident $guard:ident $pre:ident $idx:tt),+) => {
+        impl<'b, $($T,)+ F, R, E> TxnSet<F, R, E> for ($(&'b iBag<$T>,)+)
+        where
+            $($T: Clone,)+
+            F: FnOnce(($(&mut $T,)+)) -> Result<R, E>,
+        {
+            fn run(self, f: F) -> Result<Result<R, E>, PoisonedBag> {
+                $(let mut $guard = None;)+
+                for i in lock_order(&[$(self.$idx.addr()),+]) {
+                    let locked = match i {
+                        $($idx => self.$idx.try_write().map(|guard| $guard = Some(guard)),)+
+                        _ => unreachable!(),
+                    };
+                    if let Err(err) = locked {
+                        $(if let Some(guard) = $guard.take() {
+                            release(guard);
+                        })+
+                        return Err(err);
+                    }
+                }
+                $(let $guard = $guard.unwrap();)+
+                let pre = panic::catch_unwind(AssertUnwindSafe(|| ($((*$guard).clone(),)+)));
+                let ($($pre,)+) = match pre {
+                    Ok(pre) => pre,
+                    Err(payload) => {
+                        $(release($guard);)+
+                        panic::resume_unwind(payload)
+                    }
+                };
+                $(let mut $guard = $guard;)+
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    f(($(&mut *$guard,)+))
+                }));
+                if !matches!(result, Ok(Ok(_))) {
+                    $(
+                        *$guard = $pre;
+                        $guard.mark_unchanged();
+                    )+
+                }
+                $(drop($guard);)+
+                match result {
+                    Ok(result) => Ok(result),
+                    Err(payload) => panic::resume_unwind(payload),
+                }
+            }
+        }
+    };
+}
+
+tuple_txn!(A a pa 0);
+tuple_txn!(A a pa 0, B b pb 1);
+tuple_txn!(A a pa 0, B b pb 1, C c pc 2);
+tuple_txn!(A a pa 0, B b pb 1, C c pc 2, D d pd 3);
+tuple_txn!(A a pa 0, B b pb 1, C c pc 2, D d pd 3, G g pg 4);
+tuple_txn!(A a pa 0, B b pb 1, C c pc 2, D d pd 3, G g pg 4, H h ph 5);
+tuple_txn!(A a pa 0, B b pb 1, C c pc 2, D d pd 3, G g pg 4, H h ph 5, I i pi 6);
+tuple_txn!(A a pa 0, B b pb 1, C c pc 2, D d pd 3, G g pg 4, H h ph 5, I i pi 6, J j pj 7);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_commit() {
+        let a = iBag::new(10);
+        let b = iBag::new(String::from("x"));
+        let r: Result<usize, ()> = txn((&a, &b), |(a, b)| {
+            *a += 1;
+            b.push('y');
+            Ok(b.len())
+        });
+        assert_eq!(r, Ok(2));
+        assert_eq!(*a.load(), 11);
+        assert_eq!(*b.load(), "xy");
+        assert_eq!((a.version(), b.version()), (1, 1));
+    }
+
+    #[test]
+    fn test_rollback_on_err() {
+        let a = iBag::new(vec![1]);
+        let b = iBag::new(vec![2]);
+        let c = iBag::new(vec![3]);
+        let bags = [&a, &b, &c];
+        let r: Result<(), &str> = txn(&bags[..], |bags| {
+            for bag in bags.iter_mut() {
+                bag.clear();
+            }
+            Err("abort")
+        });
+        assert_eq!(r, Err("abort"));
+        let first = |bag: &iBag<Vec<i32>>| bag.with_read(|v| v[0]);
+        assert_eq!((first(&a), first(&b), first(&c)), (1, 2, 3));
+        assert_eq!((a.version(), b.version(), c.version()), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_rollback_on_panic() {
+        let a = iBag::new(1);
+        let b = iBag::new(2);
+        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _: Result<(), ()> = txn((&a, &b), |(a, b)| {
+                *a = 100;
+                *b = 200;
+                panic!("txn panicked");
+            });
+        }));
+        assert!(r.is_err());
+        assert!(!a.is_poisoned() && !b.is_poisoned());
+        assert_eq!((a.with_read(|v| *v), b.with_read(|v| *v)), (1, 2));
+    }
+
+    #[test]
+    fn test_slice_rollback_on_panic() {
+        let a = iBag::new(1);
+        let b = iBag::new(2);
+        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _: Result<(), ()> = txn(&[&a, &b], |[a, _]| {
+                *a = 100;
+                panic!("txn panicked");
+            });
+        }));
+        assert!(r.is_err());
+        assert!(!a.is_poisoned());
+        assert_eq!(*a.load(), 1);
+    }
+
+    #[test]
+    fn test_poisoned_bag_releases_the_others() {
+        let healthy = iBag::new(1);
+        let poisoned = iBag::new(2);
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| poisoned.with(|_| panic!("boom"))));
+        assert!(poisoned.is_poisoned());
+
+        let mut ran = false;
+        let r: Result<Result<(), ()>, _> = try_txn((&healthy, &poisoned), |_| {
+            ran = true;
+            Ok(())
+        });
+        assert_eq!(r, Err(PoisonedBag));
+        let r: Result<Result<(), ()>, _> = try_txn(&[&poisoned, &iBag::new(3)], |_| Ok(()));
+        assert_eq!(r, Err(PoisonedBag));
+        assert!(!ran);
+
+        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _: Result<(), ()> = txn((&poisoned, &healthy), |_| Ok(()));
+        }));
+        assert!(r.is_err());
+        assert!(!healthy.is_poisoned());
+        assert_eq!((*healthy.load(), healthy.version()), (1, 0));
+        assert!(healthy.try_write_now().is_ok());
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Brittle(i32);
+
+    impl Clone for Brittle {
+        fn clone(&self) -> Self {
+            if self.0 < 0 {
+                panic!("clone failed");
+            }
+            Brittle(self.0)
+        }
+    }
+
+    #[test]
+    fn test_panicking_clone_does_not_poison() {
+        let a = iBag::new(Brittle(1));
+        let b = iBag::new(Brittle(-1));
+        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _: Result<(), ()> = txn((&a, &b), |_| Ok(()));
+        }));
+        assert!(r.is_err());
+        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _: Result<(), ()> = txn(&[&a, &b], |_| Ok(()));
+        }));
+        assert!(r.is_err());
+        assert!(!a.is_poisoned() && !b.is_poisoned());
+        assert_eq!((a.version(), b.version()), (0, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "passed twice")]
+    fn test_duplicate_bag() {
+        let a = iBag::new(1);
+        let _: Result<(), ()> = txn((&a, &a.clone()), |_| Ok(()));
+    }
+
+    #[test]
+    fn test_opposite_orders_do_not_deadlock() {
+        let a = iBag::new(1000i64);
+        let b = iBag::new(1000i64);
+        let handles: Vec<_> = (0..8)
+            .map(|n| {
+                let (a, b) = (a.clone(), b.clone());
+                thread::spawn(move || {
+                    for _ in 0..500 {
+                        let r: Result<(), ()> = if n % 2 == 0 {
+                            txn((&a, &b), |(a, b)| {
+                                *a -= 1;
+                                *b += 1;
+                                Ok(())
+                            })
+                        } else {
+                            txn(&[&b, &a], |[b, a]| {
+                                *b -= 1;
+                                *a += 1;
+                                Ok(())
+                            })
+                        };
+                        r.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(a.with_read(|v| *v) + b.with_read(|v| *v), 2000);
+    }
+}
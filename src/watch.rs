@@ -0,0 +1,196 @@
+// Copyright 2023 Brian G
+// Licensed under the MIT license (https://opensource.org/licenses/MIT)
+
+//! Change notifications for `iBag`.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::bag::{iBag, ReadGuard};
+use crate::errors::WaitTimeout;
+use crate::lock::Wait;
+
+/// A handle that waits for an iBag to change
+///
+/// Created by `iBag::subscribe()`. Each watcher remembers the last version
+/// of the bag it has seen; every released write guard produces a new
+/// version. Several changes that happen between two waits are reported as
+/// one.
+///
+/// The watcher keeps the bag alive, so waiting on a bag that no other handle
+/// can write blocks forever unless a timeout is used.
+pub struct Watcher<T> {
+    bag: iBag<T>,
+    seen: u64,
+}
+
+impl<T> Watcher<T> {
+    pub(crate) fn new(bag: iBag<T>) -> Self {
+        let seen = bag.raw().version();
+        Watcher { bag, seen }
+    }
+
+    /// Returns `true` if the bag changed since it was last seen
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(0);
+    /// let watcher = bag.subscribe();
+    /// assert!(!watcher.has_changed());
+    /// bag.with(|val| *val = 1);
+    /// assert!(watcher.has_changed());
+    /// ```
+    pub fn has_changed(&self) -> bool {
+        self.bag.raw().version() != self.seen
+    }
+
+    /// Blocks until the bag changes, then marks the change as seen
+    ///
+    /// Returns immediately if a change happened since the last wait.
+    pub fn changed(&mut self) {
+        if let Some(version) = self.bag.raw().wait_for_change(self.seen, Wait::Forever) {
+            self.seen = version;
+        }
+    }
+
+    /// Blocks until the bag changes or `timeout` elapses
+    ///
+    /// # Returns
+    /// - `Ok(())` if the bag changed; the change is marked as seen
+    /// - `Err(WaitTimeout)` if the timeout elapsed first
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// use ibag::errors::WaitTimeout;
+    /// use std::time::Duration;
+    ///
+    /// let bag = iBag::new(0);
+    /// let mut watcher = bag.subscribe();
+    /// assert_eq!(watcher.changed_timeout(Duration::from_millis(10)), Err(WaitTimeout));
+    /// bag.with(|val| *val = 1);
+    /// assert_eq!(watcher.changed_timeout(Duration::from_millis(10)), Ok(()));
+    /// ```
+    pub fn changed_timeout(&mut self, timeout: Duration) -> Result<(), WaitTimeout> {
+        match self.bag.raw().wait_for_change(self.seen, Wait::timeout(timeout)) {
+            Some(version) => {
+                self.seen = version;
+                Ok(())
+            }
+            None => Err(WaitTimeout),
+        }
+    }
+
+    /// Acquires a read lock on the latest value and marks it as seen
+    ///
+    /// # Examples
+    /// ```
+    /// use ibag::iBag;
+    /// let bag = iBag::new(0);
+    /// let mut watcher = bag.subscribe();
+    /// bag.with(|val| *val = 1);
+    /// assert_eq!(*watcher.borrow_latest(), 1);
+    /// assert!(!watcher.has_changed());
+    /// ```
+    pub fn borrow_latest(&mut self) -> ReadGuard<'_, T> {
+        let guard = self.bag.load();
+        // No writer can release while the read lock is held.
+        self.seen = self.bag.raw().version();
+        guard
+    }
+}
+
+impl<T> Clone for Watcher<T> {
+    fn clone(&self) -> Self {
+        Watcher {
+            bag: self.bag.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Watcher<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Watcher")
+            .field("bag", &self.bag)
+            .field("seen", &self.seen)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_changed_wakes_on_write() {
+        let bag = iBag::new(0);
+        let mut watcher = bag.subscribe();
+        let b = bag.clone();
+        let writer = thread::spawn(move || {
+            while b.raw().waiting() < 1 {
+                thread::yield_now();
+            }
+            b.with(|v| *v = 7);
+        });
+        watcher.changed();
+        assert_eq!(*watcher.borrow_latest(), 7);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_write_guard_drop_notifies() {
+        let bag = iBag::new(0);
+        let mut watcher = bag.subscribe();
+        let guard = bag.write();
+        assert!(!watcher.has_changed());
+        drop(guard);
+        assert!(watcher.has_changed());
+        assert_eq!(watcher.changed_timeout(Duration::from_millis(10)), Ok(()));
+        assert!(!watcher.has_changed());
+    }
+
+    #[test]
+    fn test_changes_are_coalesced() {
+        let bag = iBag::new(0);
+        let mut watcher = bag.subscribe();
+        for i in 1..=3 {
+            bag.with(|v| *v = i);
+        }
+        watcher.changed();
+        assert_eq!(*watcher.borrow_latest(), 3);
+        assert_eq!(
+            watcher.changed_timeout(Duration::from_millis(10)),
+            Err(WaitTimeout)
+        );
+    }
+
+    #[test]
+    fn test_reads_do_not_notify() {
+        let bag = iBag::new(0);
+        let watcher = bag.subscribe();
+        bag.with_read(|v| *v);
+        drop(bag.load());
+        assert!(!watcher.has_changed());
+    }
+
+    #[test]
+    fn test_many_watchers() {
+        let bag = iBag::new(0);
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let mut watcher = bag.subscribe();
+                thread::spawn(move || {
+                    watcher.changed();
+                    *watcher.borrow_latest()
+                })
+            })
+            .collect();
+        bag.with(|v| *v = 1);
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 1);
+        }
+    }
+}
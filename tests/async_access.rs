@@ -0,0 +1,123 @@
+use ibag::iBag;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+fn noop_context() -> Context<'static> {
+    Context::from_waker(Waker::noop())
+}
+
+#[test]
+fn test_async_read_write() {
+    let bag = iBag::new(1);
+    block_on(async {
+        *bag.write_async().await += 1;
+        assert_eq!(*bag.load_async().await, 2);
+        assert_eq!(bag.with_async(|v| { *v += 1; *v }).await, 3);
+        assert_eq!(bag.with_read_async(|v| *v).await, 3);
+    });
+}
+
+#[test]
+fn test_async_writer_waits_for_sync_reader() {
+    let bag = iBag::new(0);
+    let read = bag.load();
+    let b = bag.clone();
+    let writer = thread::spawn(move || block_on(b.with_async(|v| *v = 1)));
+
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(*read, 0);
+    drop(read);
+
+    writer.join().unwrap();
+    assert_eq!(*bag.load(), 1);
+}
+
+#[test]
+fn test_pending_async_writer_holds_back_readers() {
+    let bag = iBag::new(0);
+    let read = bag.load();
+
+    let mut write = pin!(bag.write_async());
+    assert!(write.as_mut().poll(&mut noop_context()).is_pending());
+    assert!(bag.try_load_now().is_err());
+
+    drop(read);
+    match write.as_mut().poll(&mut noop_context()) {
+        Poll::Ready(mut guard) => *guard = 1,
+        Poll::Pending => panic!("writer should acquire once readers are gone"),
+    }
+    assert_eq!(*bag.load(), 1);
+}
+
+#[test]
+fn test_dropped_async_writer_releases_readers() {
+    let bag = iBag::new(0);
+    let read = bag.load();
+    {
+        let mut write = Box::pin(bag.write_async());
+        assert!(write.as_mut().poll(&mut noop_context()).is_pending());
+        assert!(bag.try_load_now().is_err());
+    }
+    assert!(bag.try_load_now().is_ok());
+    drop(read);
+}
+
+#[test]
+fn test_many_async_writers() {
+    let bag = iBag::new(0);
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let bag = bag.clone();
+            thread::spawn(move || {
+                block_on(async {
+                    for _ in 0..500 {
+                        bag.with_async(|v| *v += 1).await;
+                    }
+                })
+            })
+        })
+        .collect();
+    for _ in 0..500 {
+        bag.with(|v| *v += 1);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(*bag.load(), 4500);
+}
+
+#[test]
+fn test_guards_are_send() {
+    fn assert_send<T: Send>(_: &T) {}
+    let bag = iBag::new(0);
+    let future = async {
+        let guard = bag.write_async().await;
+        std::future::ready(()).await;
+        drop(guard);
+    };
+    assert_send(&future);
+}
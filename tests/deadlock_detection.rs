@@ -0,0 +1,106 @@
+#![cfg(feature = "deadlock-detection")]
+
+use ibag::{iBag, txn, UpgradableReadGuard};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+/// Runs `f` and returns the message it panicked with
+fn panic_message<F: FnOnce()>(f: F) -> String {
+    let err = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_err();
+    match err.downcast::<String>() {
+        Ok(message) => *message,
+        Err(err) => err.downcast_ref::<&str>().unwrap().to_string(),
+    }
+}
+
+#[test]
+fn test_reentrant_write_panics() {
+    let bag = iBag::new(0);
+    let message = panic_message(|| bag.with(|_| drop(bag.load())));
+    assert!(message.contains("re-entrant acquisition"), "{}", message);
+    assert!(message.contains("tests/deadlock_detection.rs"), "{}", message);
+    // The bag is released while unwinding and usable again.
+    bag.clear_poison();
+    assert_eq!(*bag.load(), 0);
+}
+
+#[test]
+fn test_reentrant_read_panics() {
+    let bag = iBag::new(0);
+    let message = panic_message(|| bag.with_read(|_| bag.with_read(|_| ())));
+    assert!(message.contains("re-entrant acquisition"), "{}", message);
+}
+
+#[test]
+fn test_non_blocking_reentry_is_allowed() {
+    let bag = iBag::new(0);
+    bag.with(|_| assert!(bag.try_load_now().is_err()));
+    bag.with_read(|_| assert!(bag.try_load_now().is_ok()));
+}
+
+#[test]
+fn test_upgrade_over_own_read_panics() {
+    let bag = iBag::new(0);
+    let message = panic_message(|| {
+        let guard = bag.upgradable_load();
+        let _read = bag.try_load_now().unwrap();
+        UpgradableReadGuard::upgrade(guard);
+    });
+    assert!(message.contains("upgrade"), "{}", message);
+}
+
+#[test]
+fn test_lock_order_cycle_panics() {
+    let a = iBag::new(0);
+    let b = iBag::new(0);
+    a.with(|_| b.with(|_| ()));
+    let message = panic_message(|| b.with(|_| a.with(|_| ())));
+    assert!(message.contains("lock order cycle"), "{}", message);
+    assert_eq!(message.matches("created at tests/deadlock_detection.rs").count(), 3, "{}", message);
+}
+
+#[test]
+fn test_cycle_across_threads_and_bags() {
+    let a = iBag::new(0);
+    let b = iBag::new(0);
+    let c = iBag::new(0);
+    thread::scope(|s| {
+        s.spawn(|| a.with(|_| b.with(|_| ())));
+    });
+    thread::scope(|s| {
+        s.spawn(|| b.with_read(|_| c.with_read(|_| ())));
+    });
+    let message = panic_message(|| c.with(|_| a.with(|_| ())));
+    assert!(message.contains("lock order cycle"), "{}", message);
+}
+
+#[test]
+fn test_consistent_order_and_txn_pass() {
+    let a = iBag::new(1);
+    let b = iBag::new(2);
+    for _ in 0..3 {
+        a.with(|_| b.with_read(|_| ()));
+    }
+    // Transactions lock in their own global order, whatever order the bags
+    // are passed in.
+    let c = iBag::new(3);
+    let d = iBag::new(4);
+    for _ in 0..2 {
+        let _: Result<(), ()> = txn((&c, &d), |(c, d)| {
+            std::mem::swap(c, d);
+            Ok(())
+        });
+        let _: Result<(), ()> = txn((&d, &c), |(_, _)| Ok(()));
+    }
+    assert_eq!((c.with_read(|v| *v), d.with_read(|v| *v)), (3, 4));
+}
+
+#[test]
+fn test_guard_moved_to_another_thread() {
+    let bag = iBag::new(0);
+    let guard = bag.write();
+    thread::scope(|s| {
+        s.spawn(move || drop(guard));
+    });
+    bag.with(|v| *v = 1);
+}
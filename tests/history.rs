@@ -0,0 +1,203 @@
+use ibag::{iBag, txn, UpgradableReadGuard, WriteGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+#[test]
+fn test_undo_redo() {
+    let bag = iBag::with_history(0, 10);
+    assert_eq!(bag.history_len(), 0);
+    for n in 1..=3 {
+        bag.with(|v| *v = n);
+    }
+    assert_eq!(bag.history_len(), 3);
+
+    assert!(bag.undo());
+    assert!(bag.undo());
+    assert_eq!(*bag.load(), 1);
+    assert_eq!(bag.history_len(), 1);
+    assert!(bag.redo());
+    assert_eq!(*bag.load(), 2);
+    assert!(bag.undo());
+    assert!(bag.undo());
+    assert!(!bag.undo());
+    assert_eq!(*bag.load(), 0);
+
+    // A new write drops what was undone.
+    bag.with(|v| *v = 10);
+    assert!(!bag.redo());
+    assert!(bag.undo());
+    assert_eq!(*bag.load(), 0);
+}
+
+#[test]
+fn test_undo_is_a_write() {
+    let bag = iBag::with_history(0, 10);
+    let mut watcher = bag.subscribe();
+    bag.with(|v| *v = 1);
+    watcher.changed();
+    let version = bag.version();
+    assert!(bag.undo());
+    assert_eq!(bag.version(), version + 1);
+    assert!(watcher.has_changed());
+    // Nothing to undo leaves the version alone.
+    assert!(!bag.undo());
+    assert_eq!(bag.version(), version + 1);
+}
+
+#[test]
+fn test_capacity_drops_oldest() {
+    let bag = iBag::with_history(0, 2);
+    for n in 1..=5 {
+        bag.with(|v| *v = n);
+    }
+    assert_eq!(bag.history_len(), 2);
+    while bag.undo() {}
+    assert_eq!(*bag.load(), 3);
+    // Undone states count against the capacity as well.
+    assert!(bag.redo());
+    assert!(bag.redo());
+    assert!(!bag.redo());
+    assert_eq!(*bag.load(), 5);
+}
+
+#[test]
+fn test_memory_budget() {
+    let bag = iBag::builder()
+        .history_budget(100, |v: &Vec<u8>| v.len())
+        .build(Vec::new());
+    for _ in 0..10 {
+        bag.with(|v| v.extend([0; 10]));
+    }
+    // Snapshots of 0, 10, ..., 90 bytes: only the newest one fits.
+    assert_eq!(bag.history_len(), 1);
+    assert!(bag.undo());
+    assert_eq!(bag.load().len(), 90);
+    // The undone 100 bytes still fit for a redo.
+    assert!(bag.redo());
+    assert_eq!(bag.load().len(), 100);
+
+    let both = iBag::builder()
+        .history(3)
+        .history_budget(1000, |v: &Vec<u8>| v.len())
+        .build(Vec::new());
+    for _ in 0..10 {
+        both.with(|v| v.push(0));
+    }
+    assert_eq!(both.history_len(), 3);
+}
+
+#[test]
+fn test_unchanged_writes_leave_no_entry() {
+    let a = iBag::with_history(1, 10);
+    let b = iBag::with_history(2, 10);
+    let _: Result<(), ()> = txn((&a, &b), |(a, b)| {
+        *a = 10;
+        *b = 20;
+        Err(())
+    });
+    assert_eq!((a.history_len(), b.history_len()), (0, 0));
+    assert!(a.with_if_version(a.version() + 1, |v| *v = 3).is_err());
+    assert_eq!(a.history_len(), 0);
+
+    let guard = a.upgradable_load();
+    drop(guard);
+    assert_eq!(a.history_len(), 0);
+    *UpgradableReadGuard::upgrade(a.upgradable_load()) = 4;
+    assert_eq!(a.history_len(), 1);
+    assert!(a.undo());
+    assert_eq!(*a.load(), 1);
+}
+
+/// Counts its clones, which is what history snapshots cost
+#[derive(Debug)]
+struct Counted(u32);
+
+static CLONES: AtomicUsize = AtomicUsize::new(0);
+
+impl Clone for Counted {
+    fn clone(&self) -> Self {
+        CLONES.fetch_add(1, Ordering::Relaxed);
+        Counted(self.0)
+    }
+}
+
+#[test]
+fn test_upgradable_reads_snapshot_on_upgrade() {
+    let bag = iBag::with_history(Counted(1), 10);
+    for _ in 0..3 {
+        assert_eq!(bag.upgradable_load().0, 1);
+    }
+    assert_eq!(CLONES.load(Ordering::Relaxed), 0);
+
+    UpgradableReadGuard::try_upgrade(bag.upgradable_load()).unwrap().0 = 2;
+    assert_eq!(CLONES.load(Ordering::Relaxed), 1);
+    UpgradableReadGuard::upgrade(bag.upgradable_load()).0 = 3;
+    assert_eq!(CLONES.load(Ordering::Relaxed), 2);
+    assert!(bag.undo());
+    assert_eq!(bag.load().0, 2);
+}
+
+#[test]
+fn test_checkpoints() {
+    let bag = iBag::with_history(String::new(), 100);
+    assert!(!bag.undo_to("missing"));
+    bag.with(|doc| doc.push_str("title"));
+    bag.checkpoint("draft");
+    bag.with(|doc| doc.push_str(", body"));
+    bag.checkpoint("review");
+    bag.with(|doc| doc.push_str(", notes"));
+
+    assert!(bag.undo_to("draft"));
+    assert_eq!(*bag.load(), "title");
+    assert!(bag.undo_to("draft"));
+    assert!(bag.redo());
+    assert_eq!(*bag.load(), "title, body");
+    assert!(bag.undo_to("review"));
+    assert!(!bag.undo_to("missing"));
+    assert_eq!(*bag.load(), "title, body");
+    assert!(bag.undo());
+    assert_eq!(*bag.load(), "title");
+}
+
+#[test]
+fn test_without_history() {
+    let bag = iBag::new(0);
+    bag.with(|v| *v = 1);
+    bag.checkpoint("ignored");
+    assert!(!bag.undo());
+    assert!(!bag.redo());
+    assert!(!bag.undo_to("ignored"));
+    assert_eq!(bag.history_len(), 0);
+    assert_eq!(*bag.load(), 1);
+}
+
+#[test]
+fn test_concurrent_writers() {
+    let bag = iBag::with_history(0, 1000);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..50 {
+                    bag.with(|v| *v += 1);
+                    bag.history_len();
+                }
+            });
+        }
+    });
+    assert_eq!(bag.history_len(), 200);
+    let mut undone = 0;
+    while bag.undo() {
+        undone += 1;
+        assert_eq!(*bag.load(), 200 - undone);
+    }
+    assert_eq!(undone, 200);
+}
+
+#[test]
+fn test_write_guards_record() {
+    let bag = iBag::with_history(vec![1], 10);
+    bag.write().push(2);
+    let read = WriteGuard::downgrade(bag.write());
+    drop(read);
+    assert_eq!(bag.history_len(), 2);
+}
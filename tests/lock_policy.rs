@@ -0,0 +1,94 @@
+use ibag::errors::TryLockError;
+use ibag::{iBag, LockPolicy};
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Waker};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn test_default_policy() {
+    assert_eq!(iBag::new(0).policy(), LockPolicy::WriterPreferring);
+    assert_eq!(iBag::builder().build(0).policy(), LockPolicy::WriterPreferring);
+    let bag = iBag::builder().policy(LockPolicy::Fair).build(0);
+    assert_eq!(bag.policy(), LockPolicy::Fair);
+    assert_eq!(bag.clone().policy(), LockPolicy::Fair);
+}
+
+#[test]
+fn test_fair_queues_async_waiters() {
+    let bag = iBag::with_policy(0, LockPolicy::Fair);
+    let read = bag.load();
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut write = pin!(bag.write_async());
+    assert!(write.as_mut().poll(&mut cx).is_pending());
+    assert!(bag.try_load_now().is_err());
+    drop(read);
+    assert!(write.as_mut().poll(&mut cx).is_ready());
+}
+
+#[test]
+fn test_fair_timed_out_waiter_leaves_queue() {
+    let bag = iBag::with_policy(0, LockPolicy::Fair);
+    let read = bag.load();
+    assert_eq!(
+        bag.write_timeout(Duration::from_millis(10)).err(),
+        Some(TryLockError::TimedOut)
+    );
+    assert!(bag.try_load_now().is_ok());
+    drop(read);
+    assert!(bag.try_write_now().is_ok());
+}
+
+/// Measures how long a writer waits while readers keep the lock busy
+/// Runs a write against four overlapping readers and returns how many
+/// reads that started after the writer was queued still saw the old value
+fn reads_past_queued_writer(policy: LockPolicy) -> usize {
+    let bag = iBag::with_policy(0, policy);
+    let stop = Arc::new(AtomicBool::new(false));
+    let queued = Arc::new(AtomicBool::new(false));
+    let stale = Arc::new(AtomicUsize::new(0));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let bag = bag.clone();
+            let (stop, queued, stale) = (stop.clone(), queued.clone(), stale.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let after_writer = queued.load(Ordering::SeqCst);
+                    bag.with_read(|v| {
+                        if after_writer && *v == 0 {
+                            stale.fetch_add(1, Ordering::Relaxed);
+                        }
+                        thread::sleep(Duration::from_millis(1));
+                    });
+                }
+            })
+        })
+        .collect();
+    let b = bag.clone();
+    let writer = thread::spawn(move || b.with(|v| *v = 1));
+    // New readers are turned away once the writer waits or holds the lock.
+    loop {
+        match bag.try_load_now() {
+            Err(TryLockError::WouldBlock) => break,
+            Ok(guard) if *guard == 1 => break,
+            _ => thread::yield_now(),
+        }
+    }
+    queued.store(true, Ordering::SeqCst);
+    writer.join().unwrap();
+    stop.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    stale.load(Ordering::Relaxed)
+}
+
+#[test]
+fn test_readers_do_not_starve_writers() {
+    for policy in [LockPolicy::WriterPreferring, LockPolicy::Fair] {
+        assert_eq!(reads_past_queued_writer(policy), 0, "{:?}", policy);
+    }
+}
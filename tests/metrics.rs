@@ -0,0 +1,117 @@
+#![cfg(feature = "metrics")]
+
+use ibag::metrics::{LockKind, LockObserver};
+use ibag::{iBag, UpgradableReadGuard, WriteGuard};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Waker};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Default)]
+struct Recorder(Mutex<Vec<String>>);
+
+impl LockObserver for Recorder {
+    fn acquired(&self, kind: LockKind, _waited: Duration, contended: bool) {
+        self.0.lock().unwrap().push(format!("+{:?}{}", kind, if contended { "!" } else { "" }));
+    }
+
+    fn released(&self, kind: LockKind, _held: Duration) {
+        self.0.lock().unwrap().push(format!("-{:?}", kind));
+    }
+
+    fn failed(&self, kind: LockKind, _waited: Duration) {
+        self.0.lock().unwrap().push(format!("x{:?}", kind));
+    }
+}
+
+#[test]
+fn test_counts_acquisitions() {
+    let bag = iBag::new(0);
+    bag.with(|v| *v = 1);
+    bag.with_read(|v| *v);
+    drop(bag.load());
+    let guard = bag.upgradable_load();
+    *UpgradableReadGuard::upgrade(guard) = 2;
+
+    let stats = bag.stats();
+    assert_eq!(stats.read.acquisitions, 2);
+    assert_eq!(stats.upgradable.acquisitions, 1);
+    assert_eq!(stats.write.acquisitions, 2);
+    assert_eq!(stats.read.hold.count(), 2);
+    assert_eq!(stats.upgradable.hold.count(), 1);
+    assert_eq!(stats.write.hold.count(), 2);
+    assert_eq!(stats.write.contended, 0);
+    assert_eq!(stats.kind(LockKind::Write), &stats.write);
+}
+
+#[test]
+fn test_contention_and_wait_time() {
+    let bag = iBag::new(0);
+    let guard = bag.write();
+    let b = bag.clone();
+    let writer = thread::spawn(move || b.with(|v| *v = 1));
+    thread::sleep(Duration::from_millis(30));
+    drop(guard);
+    writer.join().unwrap();
+
+    let stats = bag.stats();
+    assert_eq!(stats.write.acquisitions, 2);
+    assert_eq!(stats.write.contended, 1);
+    assert!(stats.write.wait.max() >= Duration::from_millis(20));
+    assert!(stats.write.hold.max() >= Duration::from_millis(20));
+    assert!(stats.write.wait.quantile(1.0) >= Duration::from_millis(20));
+}
+
+#[test]
+fn test_failed_acquisitions() {
+    let bag = iBag::new(0);
+    let guard = bag.write();
+    assert!(bag.try_load_now().is_err());
+    assert!(bag.load_timeout(Duration::from_millis(5)).is_err());
+    {
+        let mut write = Box::pin(bag.write_async());
+        assert!(write.as_mut().poll(&mut Context::from_waker(Waker::noop())).is_pending());
+    }
+    drop(guard);
+
+    let stats = bag.stats();
+    assert_eq!(stats.read.failed, 2);
+    assert_eq!(stats.read.acquisitions, 0);
+    assert_eq!(stats.write.failed, 1);
+    assert_eq!(stats.write.acquisitions, 1);
+}
+
+#[test]
+fn test_observer_sees_events() {
+    let recorder = Arc::new(Recorder::default());
+    let bag = iBag::builder().observer(recorder.clone()).build(0);
+    bag.with(|v| *v = 1);
+    let read = WriteGuard::downgrade(bag.write());
+    assert!(bag.try_write_now().is_err());
+    drop(read);
+
+    let b = bag.clone();
+    let guard = bag.write();
+    let writer = thread::spawn(move || b.with(|v| *v = 2));
+    thread::sleep(Duration::from_millis(20));
+    drop(guard);
+    writer.join().unwrap();
+
+    let events = recorder.0.lock().unwrap();
+    assert_eq!(
+        events[..8],
+        ["+Write", "-Write", "+Write", "-Write", "xWrite", "-Read", "+Write", "-Write"]
+    );
+    assert_eq!(events[8..], ["+Write!", "-Write"]);
+}
+
+#[test]
+fn test_stats_are_per_bag() {
+    let a = iBag::new(0);
+    let b = iBag::new(0);
+    a.with(|v| *v = 1);
+    assert_eq!(a.stats().write.acquisitions, 1);
+    assert_eq!(b.stats().write.acquisitions, 0);
+    assert_eq!(a.clone().stats(), a.stats());
+}
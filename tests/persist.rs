@@ -0,0 +1,179 @@
+use ibag::persist::{Codec, PersistentBag};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+struct Numbers;
+
+impl Codec<Vec<u32>> for Numbers {
+    fn encode(&self, value: &Vec<u32>) -> io::Result<Vec<u8>> {
+        if value.contains(&u32::MAX) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "unencodable"));
+        }
+        Ok(value.iter().flat_map(|n| n.to_le_bytes()).collect())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<Vec<u32>> {
+        Ok(bytes.chunks_exact(4).map(|n| u32::from_le_bytes(n.try_into().unwrap())).collect())
+    }
+}
+
+fn temp_path(test: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "ibag-persist-{}-{}-{}",
+        std::process::id(),
+        test,
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.join("numbers")
+}
+
+fn cleanup(path: &Path) {
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+fn wal(path: &Path) -> PathBuf {
+    path.with_file_name("numbers.wal")
+}
+
+#[test]
+fn test_reopen_keeps_writes() {
+    let path = temp_path("reopen");
+    let bag = PersistentBag::open(&path, Numbers, Vec::new).unwrap();
+    assert!(path.exists());
+    let name = path.display().to_string();
+    assert!(ibag::registry::bags().iter().all(|info| info.name.as_ref() != Some(&name)));
+    for n in 1..=3 {
+        bag.with(|v| v.push(n)).unwrap();
+    }
+    assert_eq!(*bag.load(), [1, 2, 3]);
+    // Every write is snapshotted, so the log stays empty.
+    assert_eq!(fs::metadata(wal(&path)).unwrap().len(), 0);
+    drop(bag);
+
+    let bag = PersistentBag::open(&path, Numbers, || vec![99]).unwrap();
+    assert_eq!(*bag.load(), [1, 2, 3]);
+    assert_eq!(bag.path(), path);
+    cleanup(&path);
+}
+
+#[test]
+fn test_crash_recovers_from_log() {
+    let path = temp_path("crash");
+    let bag = PersistentBag::open_debounced(&path, Numbers, Vec::new, Duration::from_secs(3600)).unwrap();
+    bag.with(|v| v.push(1)).unwrap();
+    bag.with(|v| v.push(2)).unwrap();
+    assert!(fs::metadata(wal(&path)).unwrap().len() > 0);
+    // No final snapshot: only the log has the writes.
+    mem::forget(bag);
+
+    let bag = PersistentBag::open(&path, Numbers, Vec::new).unwrap();
+    assert_eq!(*bag.load(), [1, 2]);
+    assert_eq!(fs::metadata(wal(&path)).unwrap().len(), 0);
+    cleanup(&path);
+}
+
+#[test]
+fn test_torn_log_tail_is_ignored() {
+    let path = temp_path("torn");
+    let bag = PersistentBag::open_debounced(&path, Numbers, Vec::new, Duration::from_secs(3600)).unwrap();
+    bag.with(|v| v.push(1)).unwrap();
+    bag.with(|v| v.push(2)).unwrap();
+    mem::forget(bag);
+
+    // A crash in the middle of the next append.
+    let mut log = OpenOptions::new().append(true).open(wal(&path)).unwrap();
+    log.write_all(&[12, 0, 0, 0, 1, 2, 3]).unwrap();
+    drop(log);
+
+    let bag = PersistentBag::open(&path, Numbers, Vec::new).unwrap();
+    assert_eq!(*bag.load(), [1, 2]);
+    bag.with(|v| v.push(3)).unwrap();
+    drop(bag);
+    let bag = PersistentBag::open(&path, Numbers, Vec::new).unwrap();
+    assert_eq!(*bag.load(), [1, 2, 3]);
+    cleanup(&path);
+}
+
+#[test]
+fn test_failed_write_rolls_back() {
+    let path = temp_path("rollback");
+    let bag = PersistentBag::open(&path, Numbers, || vec![1]).unwrap();
+    let watcher = bag.reader().subscribe();
+    let version = bag.reader().version();
+
+    let err = bag.with(|v| v.push(u32::MAX)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(*bag.load(), [1]);
+    assert_eq!(bag.reader().version(), version);
+    assert!(!watcher.has_changed());
+
+    assert_eq!(bag.with(|v| v.len()).unwrap(), 1);
+    drop(bag);
+    let bag = PersistentBag::open(&path, Numbers, Vec::new).unwrap();
+    assert_eq!(*bag.load(), [1]);
+    cleanup(&path);
+}
+
+#[test]
+fn test_debounce_snapshots_and_empties_log() {
+    let path = temp_path("debounce");
+    let bag = PersistentBag::open_debounced(&path, Numbers, Vec::new, Duration::from_millis(20)).unwrap();
+    for n in 0..10 {
+        bag.with(|v| v.push(n)).unwrap();
+    }
+    let mut waited = Duration::ZERO;
+    while fs::metadata(wal(&path)).unwrap().len() > 0 {
+        assert!(waited < Duration::from_secs(10), "log never emptied");
+        thread::sleep(Duration::from_millis(10));
+        waited += Duration::from_millis(10);
+    }
+    mem::forget(bag);
+
+    let bag = PersistentBag::open(&path, Numbers, Vec::new).unwrap();
+    assert_eq!(bag.load().len(), 10);
+    cleanup(&path);
+}
+
+#[test]
+fn test_flush_and_concurrent_writers() {
+    let path = temp_path("flush");
+    let bag = PersistentBag::open_debounced(&path, Numbers, Vec::new, Duration::from_secs(3600)).unwrap();
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for n in 0..25 {
+                    bag.with(|v| v.push(n)).unwrap();
+                }
+            });
+        }
+    });
+    bag.flush().unwrap();
+    assert_eq!(fs::metadata(wal(&path)).unwrap().len(), 0);
+    mem::forget(bag);
+
+    let bag = PersistentBag::open(&path, Numbers, Vec::new).unwrap();
+    assert_eq!(bag.load().len(), 100);
+    cleanup(&path);
+}
+
+#[test]
+fn test_corrupt_snapshot() {
+    let path = temp_path("corrupt");
+    let bag = PersistentBag::open(&path, Numbers, || vec![7]).unwrap();
+    drop(bag);
+    let mut bytes = fs::read(&path).unwrap();
+    *bytes.last_mut().unwrap() ^= 1;
+    fs::write(&path, &bytes).unwrap();
+
+    let err = PersistentBag::open(&path, Numbers, Vec::new).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    cleanup(&path);
+}
@@ -0,0 +1,125 @@
+use ibag::registry::{self, BagInfo, LockKind};
+use ibag::{iBag, UpgradableReadGuard};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Finds the registry entry of the bag with the given name
+fn find(name: &str) -> Option<BagInfo> {
+    registry::bags().into_iter().find(|info| info.name.as_deref() == Some(name))
+}
+
+#[test]
+fn test_lists_named_bags() {
+    let bag = iBag::named("registry-list", String::from("value"));
+    let info = find("registry-list").unwrap();
+    assert_eq!(info.type_name, "alloc::string::String");
+    assert!(!info.is_locked());
+    assert!(info.holders.is_empty());
+    assert_eq!(bag.name(), Some("registry-list"));
+    assert!(format!("{:?}", bag).contains("registry-list"));
+
+    // Unnamed bags stay out of the registry.
+    let unnamed = iBag::new(0u8);
+    assert!(registry::bags().iter().all(|info| info.name.is_some()));
+    drop(unnamed);
+}
+
+#[test]
+fn test_reports_holders_and_threads() {
+    let bag = iBag::builder().name("registry-holders").build(0);
+    let read = bag.load();
+    let (locked, release) = (mpsc::channel(), mpsc::channel::<()>());
+    let b = bag.clone();
+    let other = thread::Builder::new()
+        .name("holder".into())
+        .spawn(move || {
+            let _guard = b.upgradable_load();
+            locked.0.send(()).unwrap();
+            release.1.recv().unwrap();
+        })
+        .unwrap();
+    locked.1.recv().unwrap();
+
+    let info = find("registry-holders").unwrap();
+    assert_eq!((info.readers, info.upgradable, info.writer), (1, true, false));
+    assert_eq!(info.holders.len(), 2);
+    let upgradable = info.holders.iter().find(|h| h.kind == LockKind::Upgradable).unwrap();
+    assert_eq!(upgradable.thread_name.as_deref(), Some("holder"));
+    let reader = info.holders.iter().find(|h| h.kind == LockKind::Read).unwrap();
+    assert_eq!(reader.thread, thread::current().id());
+
+    release.0.send(()).unwrap();
+    other.join().unwrap();
+    drop(read);
+    assert!(find("registry-holders").unwrap().holders.is_empty());
+}
+
+#[test]
+fn test_tracks_conversions() {
+    let bag = iBag::named("registry-upgrade", 0);
+    let guard = UpgradableReadGuard::upgrade(bag.upgradable_load());
+    let kinds = |info: BagInfo| info.holders.iter().map(|h| h.kind).collect::<Vec<_>>();
+    assert_eq!(kinds(find("registry-upgrade").unwrap()), [LockKind::Write]);
+    let guard = ibag::WriteGuard::downgrade(guard);
+    assert_eq!(kinds(find("registry-upgrade").unwrap()), [LockKind::Read]);
+    drop(guard);
+    assert!(kinds(find("registry-upgrade").unwrap()).is_empty());
+}
+
+#[test]
+fn test_drop_unregisters() {
+    let bag = iBag::named("registry-drop", 0);
+    let weak = bag.downgrade();
+    let clone = bag.clone();
+    drop(bag);
+    assert!(find("registry-drop").is_some());
+    drop(clone);
+    assert!(find("registry-drop").is_none());
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_report_does_not_wait_for_locks() {
+    let bag = iBag::named("registry-report", vec![1]);
+    let guard = bag.write();
+    let b = bag.clone();
+    let writer = thread::spawn(move || b.with(|v| v.push(2)));
+    while find("registry-report").unwrap().waiting_writers == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let report = registry::report();
+    let line = report.lines().position(|l| l.contains("\"registry-report\"")).unwrap();
+    let lines: Vec<_> = report.lines().collect();
+    assert!(lines[line].contains("alloc::vec::Vec<i32>"), "{}", report);
+    assert!(lines[line].contains("1 waiting writer(s)"), "{}", report);
+    assert!(lines[line + 1].contains("Write held by"), "{}", report);
+    registry::dump();
+
+    drop(guard);
+    writer.join().unwrap();
+    assert_eq!(*bag.load(), [1, 2]);
+}
+
+#[test]
+fn test_unwrapped_and_detached_bags() {
+    let bag = iBag::named("registry-unwrap", 1);
+    let other = bag.clone();
+    let bag = bag.try_unwrap().unwrap_err();
+    assert!(find("registry-unwrap").is_some());
+    drop(other);
+    assert_eq!(bag.try_unwrap().ok(), Some(1));
+    assert!(find("registry-unwrap").is_none());
+
+    let mut bag = iBag::named("registry-detach", vec![1]);
+    let other = bag.clone();
+    bag.make_mut().push(2);
+    let entries: Vec<_> = registry::bags()
+        .into_iter()
+        .filter(|info| info.name.as_deref() == Some("registry-detach"))
+        .collect();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(other.into_inner(), Some(vec![1]));
+    assert_eq!(find("registry-detach").map(|info| info.version), Some(1));
+}
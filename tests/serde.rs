@@ -0,0 +1,99 @@
+#![cfg(feature = "serde")]
+
+use ibag::errors::InvalidThreadAccess;
+use ibag::sendable::{SendableOption, SendableResult};
+use ibag::{iBag, iCell};
+use std::collections::BTreeMap;
+use std::thread;
+
+#[test]
+fn test_bag_round_trip() {
+    let bag = iBag::new(BTreeMap::from([("a", 1), ("b", 2)]));
+    let json = serde_json::to_string(&bag).unwrap();
+    assert_eq!(json, r#"{"a":1,"b":2}"#);
+
+    let back: iBag<BTreeMap<String, i32>> = serde_json::from_str(&json).unwrap();
+    assert_eq!(back.load()["b"], 2);
+    // Nested bags serialize their values too.
+    let nested = iBag::new(vec![iBag::new(1), iBag::new(2)]);
+    assert_eq!(serde_json::to_string(&nested).unwrap(), "[1,2]");
+}
+
+#[test]
+fn test_bag_serializes_alongside_readers() {
+    let bag = iBag::new(vec![1, 2, 3]);
+    thread::scope(|s| {
+        let _reader = bag.load();
+        let json = s.spawn(|| serde_json::to_string(&bag).unwrap()).join().unwrap();
+        assert_eq!(json, "[1,2,3]");
+    });
+}
+
+#[test]
+fn test_poisoned_bag_fails() {
+    let bag = iBag::new(0);
+    let b = bag.clone();
+    let _ = thread::spawn(move || b.with(|_| panic!("boom"))).join();
+    let err = serde_json::to_string(&bag).unwrap_err();
+    assert!(err.to_string().contains("poison"), "{}", err);
+}
+
+#[test]
+fn test_cell_round_trip() {
+    let cell = iCell::new(String::from("owned"), false);
+    let json = serde_json::to_string(&cell).unwrap();
+    assert_eq!(json, r#""owned""#);
+    let back: iCell<String> = serde_json::from_str(&json).unwrap();
+    assert!(back.is_valid());
+    assert_eq!(back.try_get().unwrap(), "owned");
+}
+
+#[test]
+fn test_cell_fails_on_foreign_thread() {
+    let cell = iCell::new(42, false);
+    let err = thread::scope(|s| s.spawn(|| serde_json::to_string(&cell).unwrap_err()).join().unwrap());
+    assert_eq!(err.to_string(), InvalidThreadAccess.to_string());
+    let result = thread::scope(|s| {
+        s.spawn(|| cell.try_serialize(&mut serde_json::Serializer::new(Vec::new())).map(|r| r.is_ok()))
+            .join()
+            .unwrap()
+    });
+    assert!(matches!(result, Err(InvalidThreadAccess)));
+}
+
+#[test]
+fn test_sendable_option() {
+    let some = SendableOption::new(5);
+    assert_eq!(serde_json::to_string(&some).unwrap(), "5");
+    assert_eq!(serde_json::to_string(&SendableOption::<i32>::None).unwrap(), "null");
+
+    let back: SendableOption<i32> = serde_json::from_str("7").unwrap();
+    assert_eq!(*back.unwrap().lock().unwrap(), 7);
+    let none: SendableOption<i32> = serde_json::from_str("null").unwrap();
+    assert!(none.is_none());
+}
+
+#[test]
+fn test_sendable_result() {
+    let ok: SendableResult<i32, String> = SendableResult::new(1);
+    let err: SendableResult<i32, String> = SendableResult::Err("bad".into());
+    assert_eq!(serde_json::to_string(&ok).unwrap(), r#"{"Ok":1}"#);
+    assert_eq!(serde_json::to_string(&err).unwrap(), r#"{"Err":"bad"}"#);
+
+    let back: SendableResult<i32, String> = serde_json::from_str(r#"{"Err":"bad"}"#).unwrap();
+    assert_eq!(back.unwrap_err(), "bad");
+    let back: SendableResult<i32, String> = serde_json::from_str(r#"{"Ok":3}"#).unwrap();
+    assert_eq!(*back.unwrap().lock().unwrap(), 3);
+}
+
+#[test]
+fn test_poisoned_sendable_fails() {
+    let some = SendableOption::new(0);
+    let inner = some.clone().unwrap();
+    let _ = thread::spawn(move || {
+        let _guard = inner.lock().unwrap();
+        panic!("boom");
+    })
+    .join();
+    assert!(serde_json::to_string(&some).is_err());
+}